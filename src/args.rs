@@ -1,42 +1,255 @@
+use crate::cidr::Cidr;
 use crate::errors::*;
+use crate::forwarded::ForwardedHeader;
+use crate::limits::parse_bytes;
+use crate::listener::parse_mode;
+use crate::noise::{PrivateKey, PublicKey};
+use crate::profiles;
+use crate::rules::{self, Action, Pattern, Rule};
+use crate::upstream::Upstream;
+use std::fmt;
 use std::io::stdout;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
 use structopt::clap::{AppSettings, Shell};
 use structopt::StructOpt;
 
-#[derive(Debug, StructOpt)]
+pub const ENV_CONFIG: &str = "SDE_CONFIG";
+pub const ENV_RESOLVER_IP: &str = "SDE_DOH_IP";
+pub const ENV_RESOLVER_NAME: &str = "SDE_DOH_NAME";
+pub const ENV_PROXY: &str = "SDE_PROXY";
+
+#[derive(Debug, Clone, StructOpt)]
 #[structopt(global_settings = &[AppSettings::ColoredHelp])]
 pub struct Args {
     /// Verbose logging output (Can be set multiple times)
     #[structopt(short, long, global = true, parse(from_occurrences))]
     pub verbose: u8,
-    #[structopt(long, global = true, default_value = "1.1.1.1", env = "SDE_DOH_IP")]
+    /// Load settings from a TOML config file, command line flags and environment variables take precedence
+    #[structopt(short, long, global = true, env = ENV_CONFIG)]
+    pub config: Option<PathBuf>,
+    #[structopt(long, global = true, default_value = "1.1.1.1", env = ENV_RESOLVER_IP)]
     pub resolver_ip: String,
-    #[structopt(long, global = true, default_value = "1.1.1.1", env = "SDE_DOH_NAME")]
+    #[structopt(long, global = true, default_value = "1.1.1.1", env = ENV_RESOLVER_NAME)]
     pub resolver_name: String,
     #[structopt(subcommand)]
     pub subcommand: SubCommand,
 }
 
+// the subcommand is only parsed once, boxing isn't worth it
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone, StructOpt)]
 pub enum SubCommand {
     Connect(Connect),
     Resolve(Resolve),
     Tunnel(Tunnel),
     Backend(Backend),
-    // Ping(Ping),
+    Profile(Profile),
+    Config(Config),
+    Rules(Rules),
+    Ping(Ping),
+    Keygen(Keygen),
     Completions(Completions),
 }
 
 #[derive(Debug, Clone, StructOpt)]
 pub struct Proxy {
     /// The websocket proxy server to connect to with TLSv1.3+ECH. Format is example.com, port 443 and https is implied.
-    #[structopt(long = "proxy", env = "SDE_PROXY")]
-    pub proxy_addr: String,
+    #[structopt(long = "proxy", env = ENV_PROXY)]
+    pub proxy_addr: Option<String>,
     #[structopt(long = "proxy-port", default_value = "443")]
     pub proxy_port: u16,
     /// Use ws:// instead of wss://
     #[structopt(long)]
     pub skip_tls: bool,
+    /// What to do if no ECHConfig is available for the proxy (prefer-ech, none)
+    #[structopt(long, default_value = "prefer-ech")]
+    pub ech: EchMode,
+    /// Additional CA bundles (PEM) to trust for the proxy connection
+    #[structopt(long = "ca-file")]
+    pub ca_files: Vec<PathBuf>,
+    /// Also trust the certificates of the system trust store
+    #[structopt(long)]
+    pub system_roots: bool,
+    /// Require the server certificate to match this SPKI hash (sha256/<base64>)
+    #[structopt(long = "pin")]
+    pub pins: Vec<String>,
+    /// The Host of the websocket request if it differs from --proxy, for domain fronting
+    #[structopt(long = "proxy-host")]
+    pub proxy_host: Option<String>,
+    /// The path of the websocket endpoint
+    #[structopt(long = "proxy-path", default_value = "/connect")]
+    pub proxy_path: String,
+    /// The name of the proxy given with --proxy, used in logs
+    #[structopt(long = "proxy-name", default_value = "default")]
+    pub proxy_name: String,
+    /// How to pick a proxy if multiple are configured
+    #[structopt(long, default_value = "ordered", possible_values = Strategy::variants())]
+    pub strategy: Strategy,
+    /// Number of consecutive failures until a proxy is skipped for a while
+    #[structopt(long, default_value = "3")]
+    pub failure_threshold: u32,
+    /// Seconds to skip a failing proxy before it's tried again
+    #[structopt(long, default_value = "30")]
+    pub cooldown: u64,
+    /// Connect to the proxy through a socks5 or http proxy (eg. `socks5://127.0.0.1:9050`)
+    #[structopt(long)]
+    pub upstream: Option<Upstream>,
+    /// The public key of the backend, the connection inside the websocket is
+    /// encrypted with it (see `keygen`)
+    #[structopt(long)]
+    pub backend_key: Option<PublicKey>,
+    /// The private key to authenticate with at the backend, a new key is used
+    /// for every connection otherwise
+    #[structopt(long)]
+    pub identity: Option<PathBuf>,
+    /// The destination that's requested to measure the latency of a proxy
+    #[structopt(long, default_value = "textsecure-service.whispersystems.org:443")]
+    pub probe_addr: String,
+    /// Additional proxies from the config file
+    #[structopt(skip)]
+    pub proxies: Vec<Endpoint>,
+}
+
+impl Proxy {
+    pub fn addr(&self) -> Result<&str> {
+        self.proxy_addr
+            .as_deref()
+            .ok_or_else(|| anyhow!("No proxy configured, use --proxy or the config file"))
+    }
+
+    /// The value of the Host header, this is the same as the proxy unless domain fronting is used
+    pub fn host(&self) -> Result<&str> {
+        match &self.proxy_host {
+            Some(host) => Ok(host),
+            None => self.addr(),
+        }
+    }
+
+    /// All configured proxies, the one given with --proxy comes first
+    pub fn endpoints(&self) -> Result<Vec<Endpoint>> {
+        let mut endpoints = Vec::new();
+        if self.proxy_addr.is_some() {
+            let mut proxy = self.clone();
+            proxy.proxies.clear();
+            endpoints.push(Endpoint {
+                name: self.proxy_name.clone(),
+                proxy,
+            });
+        }
+        endpoints.extend(self.proxies.iter().cloned().map(|mut endpoint| {
+            if endpoint.proxy.upstream.is_none() {
+                endpoint.proxy.upstream = self.upstream.clone();
+            }
+            if endpoint.proxy.backend_key.is_none() {
+                endpoint.proxy.backend_key = self.backend_key;
+            }
+            if endpoint.proxy.identity.is_none() {
+                endpoint.proxy.identity = self.identity.clone();
+            }
+            endpoint
+        }));
+
+        if endpoints.is_empty() {
+            bail!("No proxy configured, use --proxy or the config file");
+        }
+        for (i, endpoint) in endpoints.iter().enumerate() {
+            if endpoints[..i].iter().any(|e| e.name == endpoint.name) {
+                bail!("Proxy name is used more than once: {:?}", endpoint.name);
+            }
+            if !endpoint.proxy.proxy_path.starts_with('/') {
+                bail!(
+                    "Proxy path needs to start with a slash: {:?}",
+                    endpoint.proxy.proxy_path
+                );
+            }
+        }
+        Ok(endpoints)
+    }
+}
+
+/// A named proxy with its own settings
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    pub name: String,
+    pub proxy: Proxy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+    /// Use the first healthy proxy in the order they are configured
+    Ordered,
+    /// Rotate through the healthy proxies
+    RoundRobin,
+    /// Use the healthy proxy with the lowest observed handshake latency
+    LowestLatency,
+}
+
+impl Strategy {
+    pub fn variants() -> &'static [&'static str] {
+        &["ordered", "round-robin", "lowest-latency"]
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Strategy> {
+        match s {
+            "ordered" => Ok(Strategy::Ordered),
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "lowest-latency" => Ok(Strategy::LowestLatency),
+            _ => bail!("Unknown strategy: {:?}", s),
+        }
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Strategy::Ordered => "ordered",
+            Strategy::RoundRobin => "round-robin",
+            Strategy::LowestLatency => "lowest-latency",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EchMode {
+    /// Use ECH if available, otherwise fall back to a plain SNI
+    Prefer,
+    /// Don't attempt ECH at all
+    None,
+}
+
+impl FromStr for EchMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<EchMode> {
+        match s {
+            "prefer-ech" => Ok(EchMode::Prefer),
+            // rustls can neither send ECH nor a GREASE ECH extension, so these
+            // modes couldn't do anything but fail every connection
+            "require-ech" | "grease-only" => bail!(
+                "{:?} is not available, the tls backend doesn't support ECH yet",
+                s
+            ),
+            "none" => Ok(EchMode::None),
+            _ => bail!("Unknown ech mode: {:?}", s),
+        }
+    }
+}
+
+impl fmt::Display for EchMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            EchMode::Prefer => "prefer-ech",
+            EchMode::None => "none",
+        };
+        write!(f, "{}", s)
+    }
 }
 
 /// Setup a tunnel with TLSv1.3+ECH and connect to a specific address
@@ -59,31 +272,304 @@ pub struct Resolve {
 pub struct Tunnel {
     #[structopt(flatten)]
     pub proxy: Proxy,
+    /// Forward matching destinations through the proxy (eg. `*.signal.org:443`)
     #[structopt(short = "F", long)]
-    pub forward: Vec<String>,
+    pub forward: Vec<Pattern>,
+    /// Ordered rules like `pattern -> proxy|proxy:<name>|direct|block`, evaluated before --forward
+    #[structopt(short = "R", long = "rule")]
+    pub rules: Vec<Rule>,
+    /// Forward the destinations of a bundled profile (eg. `signal`) or profile file
+    #[structopt(long = "profile")]
+    pub profiles: Vec<String>,
+    /// Limit the upload of all connections to this many bytes per second (eg. `512K`)
+    #[structopt(long, parse(try_from_str = parse_bytes))]
+    pub upload_limit: Option<u64>,
+    /// Limit the download of all connections to this many bytes per second (eg. `2M`)
+    #[structopt(long, parse(try_from_str = parse_bytes))]
+    pub download_limit: Option<u64>,
+    /// Address to listen on, use `unix:/path/to/sock` for a unix socket
     #[structopt(long, default_value = "127.0.0.1:1090")]
     pub bind: String,
+    /// Permissions of the unix socket in octal notation (eg. `600`)
+    #[structopt(long, parse(try_from_str = parse_mode))]
+    pub socket_mode: Option<u32>,
+    /// Also use the --upstream for connections that don't go through the proxy
+    #[structopt(long)]
+    pub direct_upstream: bool,
+    /// Refuse requests that don't match a rule instead of connecting directly,
+    /// `direct` rules are not allowed
+    #[structopt(long)]
+    pub strict: bool,
+    /// Record every requested destination and the decision in this file,
+    /// see `rules suggest`
+    #[structopt(long)]
+    pub learn: Option<PathBuf>,
+    /// Measure the latency of every proxy in this interval (seconds), 0 to disable.
+    /// Defaults to 60 with the lowest-latency strategy.
+    #[structopt(long)]
+    pub probe_interval: Option<u64>,
+    /// Only accept local connections from processes running as this user id
+    #[structopt(long = "allow-uid")]
+    pub allowed_uids: Vec<u32>,
+    /// Only accept local connections from processes running with this group id
+    #[structopt(long = "allow-gid")]
+    pub allowed_gids: Vec<u32>,
 }
 
 /// Run the backend proxy server
 #[derive(Debug, Clone, StructOpt)]
 pub struct Backend {
+    /// Address to listen on, use `unix:/path/to/sock` for a unix socket
+    #[structopt(long, default_value = "127.0.0.1:3030")]
+    pub bind: String,
+    /// Permissions of the unix socket in octal notation (eg. `660`)
+    #[structopt(long, parse(try_from_str = parse_mode))]
+    pub socket_mode: Option<u32>,
+    /// Use the --forwarded-header from these proxies (eg. `127.0.0.1`)
+    #[structopt(long = "trusted-proxy")]
+    pub trusted_proxies: Vec<Cidr>,
+    /// The header trusted proxies put the client address in, other headers are ignored
+    #[structopt(long, default_value = "x-forwarded-for", possible_values = &["x-forwarded-for", "forwarded", "cf-connecting-ip"])]
+    pub forwarded_header: ForwardedHeader,
+    /// Use the --forwarded-header from connections over the unix socket
+    #[structopt(long)]
+    pub trust_unix_socket: bool,
+    /// Expect a HAProxy PROXY protocol v1/v2 header on every connection
+    #[structopt(long)]
+    pub proxy_protocol: bool,
+    /// Allow connections to matching destinations (eg. `*.signal.org:443`)
     #[structopt(short = "A", long = "allow")]
-    pub allowed: Vec<String>,
+    pub allowed: Vec<Pattern>,
+    /// Ordered rules like `pattern -> allow|block`, evaluated before --allow
+    #[structopt(short = "R", long = "rule")]
+    pub rules: Vec<Rule>,
+    /// Allow the destinations of a bundled profile (eg. `signal`) or profile file
+    #[structopt(long = "profile")]
+    pub profiles: Vec<String>,
+    /// Allow connections into special purpose ranges like private networks (eg. `10.0.0.0/8`)
+    #[structopt(long = "allow-cidr")]
+    pub allowed_cidrs: Vec<Cidr>,
+    /// How to resolve destinations, with dns-over-https or the system resolver
+    #[structopt(long, default_value = "doh", possible_values = &["doh", "system"])]
+    pub resolver: ResolverMode,
+    /// Maximum number of concurrent sessions per client
+    #[structopt(long)]
+    pub max_sessions_per_client: Option<usize>,
+    /// Maximum number of new connections per second per client
+    #[structopt(long)]
+    pub max_connect_rate: Option<u64>,
+    /// Maximum number of concurrent outbound connections in total
+    #[structopt(long)]
+    pub max_connections: Option<usize>,
+    /// Limit the bandwidth of all clients to this many bytes per second (eg. `10M`)
+    #[structopt(long, parse(try_from_str = parse_bytes))]
+    pub bandwidth_limit: Option<u64>,
+    /// Limit the bandwidth of each client to this many bytes per second
+    #[structopt(long, parse(try_from_str = parse_bytes))]
+    pub client_bandwidth_limit: Option<u64>,
+    /// Limit the bandwidth to each destination to this many bytes per second
+    #[structopt(long, parse(try_from_str = parse_bytes))]
+    pub destination_bandwidth_limit: Option<u64>,
+    /// Number of bytes each client may transfer per day (eg. `1G`)
+    #[structopt(long, parse(try_from_str = parse_bytes))]
+    pub daily_quota: Option<u64>,
+    /// Number of bytes each client may transfer per month
+    #[structopt(long, parse(try_from_str = parse_bytes))]
+    pub monthly_quota: Option<u64>,
+    /// Persist the quota usage in this file
+    #[structopt(long)]
+    pub quota_file: Option<PathBuf>,
+    /// The private key of the backend, clients need to encrypt the connection
+    /// inside the websocket with the public key (see `keygen`)
+    #[structopt(long)]
+    pub noise_key: Option<PathBuf>,
+    /// Only accept clients that authenticate with this public key, requires --noise-key
+    #[structopt(long = "allow-client")]
+    pub allowed_clients: Vec<PublicKey>,
     /// Ping interval in seconds to prevent connection timeouts
     #[structopt(long)]
     pub ping_interval: Option<u64>,
 }
 
-/*
-/// Check if we can successfully tunnel to signal servers
+impl Tunnel {
+    pub fn probe_interval(&self) -> Option<Duration> {
+        let secs = match self.probe_interval {
+            Some(secs) => secs,
+            None if self.proxy.strategy == Strategy::LowestLatency => 60,
+            None => 0,
+        };
+        if secs > 0 {
+            Some(Duration::from_secs(secs))
+        } else {
+            None
+        }
+    }
+
+    /// All rules in evaluation order, unmatched requests go direct unless
+    /// strict mode is enabled
+    pub fn rules(&self) -> Result<Vec<Rule>> {
+        if self.strict {
+            rules::validate(&self.rules, &[Action::Proxy, Action::Block])?;
+        } else {
+            rules::validate(&self.rules, &[Action::Proxy, Action::Direct, Action::Block])?;
+        }
+        for rule in &self.rules {
+            if let Action::Via(name) = &rule.action {
+                let endpoints = self.proxy.endpoints()?;
+                if !endpoints.iter().any(|e| &e.name == name) {
+                    bail!("Rule refers to an unknown proxy {:?}: {}", name, rule);
+                }
+            }
+        }
+        let mut rules = self.rules.clone();
+        rules.extend(
+            self.forward
+                .iter()
+                .map(|p| Rule::new(p.clone(), Action::Proxy)),
+        );
+        for name in &self.profiles {
+            let profile = profiles::Profile::load(name)?;
+            rules.extend(
+                profile
+                    .patterns
+                    .into_iter()
+                    .map(|p| Rule::new(p, Action::Proxy)),
+            );
+        }
+        Ok(rules)
+    }
+}
+
+impl Backend {
+    /// Load the private key used to encrypt the channel inside the websocket
+    pub fn noise_key(&self) -> Result<Option<PrivateKey>> {
+        match &self.noise_key {
+            Some(path) => Ok(Some(PrivateKey::load(path)?)),
+            None if !self.allowed_clients.is_empty() => {
+                bail!("Allowing specific clients requires a noise key")
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// All rules in evaluation order, unmatched requests are blocked
+    pub fn rules(&self) -> Result<Vec<Rule>> {
+        rules::validate(&self.rules, &[Action::Allow, Action::Block])?;
+        let mut rules = self.rules.clone();
+        rules.extend(
+            self.allowed
+                .iter()
+                .map(|p| Rule::new(p.clone(), Action::Allow)),
+        );
+        for name in &self.profiles {
+            let profile = profiles::Profile::load(name)?;
+            rules.extend(
+                profile
+                    .patterns
+                    .into_iter()
+                    .map(|p| Rule::new(p, Action::Allow)),
+            );
+        }
+        Ok(rules)
+    }
+}
+
+/// Show the bundled rule profiles or the entries of a profile
+#[derive(Debug, Clone, StructOpt)]
+pub struct Profile {
+    pub name: Option<String>,
+}
+
+/// Work with config files
+#[derive(Debug, Clone, StructOpt)]
+pub enum Config {
+    /// Validate the config file and print the effective configuration
+    Check,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResolverMode {
+    Doh,
+    System,
+}
+
+impl FromStr for ResolverMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ResolverMode> {
+        match s {
+            "doh" => Ok(ResolverMode::Doh),
+            "system" => Ok(ResolverMode::System),
+            _ => bail!("Unknown resolver: {:?}", s),
+        }
+    }
+}
+
+impl fmt::Display for ResolverMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResolverMode::Doh => write!(f, "doh"),
+            ResolverMode::System => write!(f, "system"),
+        }
+    }
+}
+
+/// Work with rules
+#[derive(Debug, Clone, StructOpt)]
+pub enum Rules {
+    /// Suggest entries for destinations recorded with `tunnel --learn` that didn't match a rule
+    Suggest(Suggest),
+    /// Print entries for the servers in the config of signal-desktop
+    FromSignal(FromSignal),
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct Suggest {
+    /// The file written by `tunnel --learn`
+    pub path: PathBuf,
+    /// Print -A entries for the backend instead of -F entries
+    #[structopt(long)]
+    pub backend: bool,
+    /// Only suggest destinations in this domain (eg. `signal.org`)
+    #[structopt(long = "domain")]
+    pub domains: Vec<String>,
+    /// Merge hosts that share a parent domain into a glob like `*.signal.org:443`
+    #[structopt(long)]
+    pub glob: bool,
+    /// Ignore destinations that were requested less often
+    #[structopt(long, default_value = "1")]
+    pub min_count: u64,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct FromSignal {
+    /// config/production.json (with default.json next to it), the app.asar of
+    /// an installed signal-desktop or a directory it was extracted to
+    pub path: PathBuf,
+    /// Print -A entries for the backend instead of -F entries
+    #[structopt(long)]
+    pub backend: bool,
+    /// Print a profile file that can be used with --profile
+    #[structopt(long, conflicts_with = "backend")]
+    pub profile: bool,
+}
+
+/// Check if we can successfully tunnel to signal servers and measure the latency of every proxy
 #[derive(Debug, Clone, StructOpt)]
 pub struct Ping {
-    /// The websocket proxy server to connect to with TLSv1.3+ECH
-    #[structopt(long, env = "SDE_PROXY_URL")]
-    pub proxy: String,
+    #[structopt(flatten)]
+    pub proxy: Proxy,
+    /// Number of probes per proxy
+    #[structopt(short = "n", long, default_value = "3")]
+    pub count: usize,
+}
+
+/// Generate a key for the backend or a client and print the public key
+#[derive(Debug, Clone, StructOpt)]
+pub struct Keygen {
+    /// The file the private key is written to, an existing key is not replaced
+    pub path: PathBuf,
 }
-*/
 
 /// Generate shell completions
 #[derive(Debug, Clone, StructOpt)]
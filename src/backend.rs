@@ -1,37 +1,229 @@
-use crate::args::Backend;
-use crate::common::{Hello, HelloResponse};
+use crate::args::{Backend, ResolverMode, SubCommand};
+use crate::cidr::{self, Cidr};
+use crate::common::{Hello, HelloResponse, RejectReason};
+use crate::config::Loader;
+use crate::connect;
+use crate::dns::Resolver;
 use crate::errors::*;
-use crate::rules;
+use crate::forwarded::{self, ForwardedHeader};
+use crate::limits::{Limiter, Limits};
+use crate::listener::{Listener, PeerAddr};
+use crate::noise::{self, PrivateKey, PublicKey, Session};
+use crate::proxy_protocol;
+use crate::quota::{self, Quotas};
+use crate::reload::{self, Shared};
+use crate::rules::{self, Action, Rule};
 use futures::{select, FutureExt, SinkExt, StreamExt};
+use std::net::IpAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::net::lookup_host;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time;
+use warp::http::{HeaderMap, Request};
+use warp::hyper::server::conn::Http;
+use warp::hyper::service::{service_fn, Service};
+use warp::hyper::Body;
 use warp::ws::{Message, WebSocket};
 use warp::Filter;
 
-async fn handle(args: Arc<Backend>, mut ws: WebSocket) -> Result<()> {
-    info!("Websocket client connected");
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct State {
+    rules: Vec<Rule>,
+    allowed_cidrs: Vec<Cidr>,
+    resolver: ResolverMode,
+    limits: Limits,
+    trusted_proxies: Vec<Cidr>,
+    forwarded_header: ForwardedHeader,
+    trust_unix_socket: bool,
+    noise_key: Option<PrivateKey>,
+    allowed_clients: Vec<PublicKey>,
+}
+
+/// The address of the connecting peer, injected into every request
+#[derive(Debug, Clone, Copy)]
+struct Peer(PeerAddr);
+
+impl State {
+    fn new(args: &Backend) -> Result<State> {
+        let rules = args.rules()?;
+        let noise_key = args.noise_key()?;
+        Ok(State {
+            rules,
+            allowed_cidrs: args.allowed_cidrs.clone(),
+            resolver: args.resolver,
+            limits: Limits {
+                sessions_per_client: args.max_sessions_per_client,
+                connect_rate: args.max_connect_rate,
+                connections: args.max_connections,
+                bandwidth: args.bandwidth_limit,
+                client_bandwidth: args.client_bandwidth_limit,
+                destination_bandwidth: args.destination_bandwidth_limit,
+                daily_quota: args.daily_quota,
+                monthly_quota: args.monthly_quota,
+            },
+            trusted_proxies: args.trusted_proxies.clone(),
+            forwarded_header: args.forwarded_header,
+            trust_unix_socket: args.trust_unix_socket,
+            noise_key,
+            allowed_clients: args.allowed_clients.clone(),
+        })
+    }
+
+    /// Requests are only allowed if the first matching rule allows them,
+    /// unmatched requests are blocked
+    fn is_allowed(&self, addr: &str) -> bool {
+        let action = rules::evaluate(addr, &self.rules).map(|rule| &rule.action);
+        action == Some(&Action::Allow)
+    }
+
+    fn is_allowed_client(&self, key: &PublicKey) -> bool {
+        self.allowed_clients.is_empty() || self.allowed_clients.contains(key)
+    }
+
+    /// Remove all addresses we shouldn't connect to, unless they've been allowed explicitly
+    fn vet(&self, ips: Vec<IpAddr>) -> Vec<IpAddr> {
+        ips.into_iter()
+            .filter(|ip| {
+                if !cidr::is_special_purpose(ip) {
+                    true
+                } else if self.allowed_cidrs.iter().any(|cidr| cidr.contains(ip)) {
+                    debug!("Address {} is explicitly allowed", ip);
+                    true
+                } else {
+                    warn!("Refusing to connect to special purpose address: {}", ip);
+                    false
+                }
+            })
+            .collect()
+    }
+}
+
+async fn reject(ws: &mut WebSocket, session: &mut Session, reason: RejectReason) -> Result<()> {
+    info!("Rejecting connection: {}", reason);
+    let msg = HelloResponse::Rejected(reason).to_vec()?;
+    ws.send(Message::binary(session.encrypt(&msg)?)).await?;
+    ws.close().await.ok();
+    Ok(())
+}
+
+async fn resolve(
+    mode: ResolverMode,
+    resolver: &Resolver,
+    host: &str,
+    port: u16,
+) -> Result<Vec<IpAddr>> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![ip]);
+    }
+    match mode {
+        ResolverMode::Doh => resolver.resolve(host).await,
+        ResolverMode::System => {
+            let addrs = lookup_host((host, port)).await?;
+            Ok(addrs.map(|addr| addr.ip()).collect())
+        }
+    }
+}
+
+async fn handle(
+    state: Arc<State>,
+    resolver: Arc<Resolver>,
+    limiter: Arc<Limiter>,
+    quotas: Arc<Quotas>,
+    mut client: String,
+    mut ws: WebSocket,
+) -> Result<()> {
+    info!("Websocket client connected: {:?}", client);
+    // the handshake is expensive, so the connection rate is checked first
+    if let Err(reason) = limiter.check_rate(&state.limits, &client) {
+        return reject(&mut ws, &mut Session::Plain, reason).await;
+    }
+
+    let mut session = match &state.noise_key {
+        Some(key) => {
+            let msg = ws
+                .next()
+                .await
+                .ok_or_else(|| anyhow!("No handshake msg received"))?
+                .context("Failed to read handshake msg")?;
+            let (mut session, key, reply) = noise::respond(key, msg.as_bytes())?;
+            ws.send(Message::binary(reply)).await?;
+            debug!("Client {:?} authenticated with key {}", client, key);
+
+            if !state.is_allowed_client(&key) {
+                warn!("Client {:?} uses a key that's not allowed: {}", client, key);
+                return reject(&mut ws, &mut session, RejectReason::Unauthorized).await;
+            }
+            if !state.allowed_clients.is_empty() {
+                // known clients are identified by their key instead of the address
+                client = key.to_string();
+            }
+            session
+        }
+        None => Session::Plain,
+    };
+
     let hello = ws
         .next()
         .await
         .ok_or_else(|| anyhow!("No hello msg received"))?
         .context("Failed to read hello msg")?;
-    let hello = Hello::parse(hello.as_bytes())?;
+    let hello = Hello::parse(&session.decrypt(hello.as_bytes())?)?;
     debug!("Received hello pkt: {:?}", hello);
+    let selection = match hello.select() {
+        Ok(selection) => selection,
+        Err(reason) => return reject(&mut ws, &mut session, reason).await,
+    };
+    let addr = rules::canonicalize(&hello.addr)?;
+
+    let _permit = match limiter.acquire(&state.limits, &client) {
+        Ok(permit) => permit,
+        Err(reason) => return reject(&mut ws, &mut session, reason).await,
+    };
+    if quotas.exceeded(&state.limits, &client) {
+        return reject(&mut ws, &mut session, RejectReason::QuotaExceeded).await;
+    }
+
+    if !state.is_allowed(&addr) {
+        warn!("Requested destination is not allowed: {:?}", addr);
+        return reject(&mut ws, &mut session, RejectReason::NotAllowed).await;
+    }
 
-    if !rules::matches(&hello.addr, &args.allowed) {
-        bail!("Requested destination is not allowed: {:?}", hello.addr);
+    let (host, port) = rules::split_addr(&addr)?;
+    let ips = match resolve(state.resolver, &resolver, host, port).await {
+        Ok(ips) => ips,
+        Err(err) => {
+            warn!("Failed to resolve {:?}: {:#}", host, err);
+            return reject(&mut ws, &mut session, RejectReason::ResolveFailed).await;
+        }
+    };
+    let ips = state.vet(ips);
+    if ips.is_empty() {
+        return reject(&mut ws, &mut session, RejectReason::ForbiddenAddress).await;
     }
 
     // TODO: timeouts
-    info!("Connecting to {:?}", hello.addr);
-    let mut remote = TcpStream::connect(&hello.addr)
-        .await
-        .with_context(|| anyhow!("Failed to connect to destination: {:?}", hello.addr))?;
+    info!("Connecting to {:?}", addr);
+    let mut remote = match connect::connect(&ips, port).await {
+        Ok(remote) => remote,
+        Err(err) => {
+            warn!("Failed to connect to destination {:?}: {:#}", addr, err);
+            return reject(&mut ws, &mut session, RejectReason::ConnectFailed).await;
+        }
+    };
 
     info!("Confirming successful connection");
-    let msg = HelloResponse::Accepted.to_vec()?;
-    ws.send(Message::binary(msg)).await?;
+    // clients without a version don't know about the selection
+    let msg = if hello.version == 0 {
+        HelloResponse::Accepted
+    } else {
+        debug!("Selected {} for {:?}", selection, client);
+        HelloResponse::Established(selection)
+    };
+    let msg = msg.to_vec()?;
+    ws.send(Message::binary(session.encrypt(&msg)?)).await?;
 
     let mut buf = [0u8; 1024];
     loop {
@@ -44,14 +236,20 @@ async fn handle(args: Arc<Backend>, mut ws: WebSocket) -> Result<()> {
                 }
                 let msg = &buf[..n];
                 trace!("Recv: {:?}", msg);
-                ws.send(Message::binary(msg)).await?;
+                limiter.throttle(&state.limits, &client, &addr, n).await;
+                ws.send(Message::binary(session.encrypt(msg)?)).await?;
+                quotas.add(&client, n);
             }
             msg = ws.next().fuse() => {
                 match msg {
                     Some(Ok(msg)) => {
                         if msg.is_binary() {
                             trace!("Send: {:?}", msg);
-                            remote.write_all(msg.as_bytes()).await?;
+                            let msg = session.decrypt(msg.as_bytes())?;
+                            let n = msg.len();
+                            limiter.throttle(&state.limits, &client, &addr, n).await;
+                            remote.write_all(&msg).await?;
+                            quotas.add(&client, n);
                         }
                     },
                     Some(Err(err)) => {
@@ -65,6 +263,11 @@ async fn handle(args: Arc<Backend>, mut ws: WebSocket) -> Result<()> {
                 }
             }
         }
+
+        if quotas.exceeded(&state.limits, &client) {
+            info!("Closing connection of {:?}, quota exceeded", client);
+            break;
+        }
     }
 
     debug!("Closing connection");
@@ -73,21 +276,193 @@ async fn handle(args: Arc<Backend>, mut ws: WebSocket) -> Result<()> {
     Ok(())
 }
 
-pub async fn run(args: Backend) -> Result<()> {
-    let args = Arc::new(args);
-    let routes = warp::path("connect")
-        .and(warp::ws())
-        .map(move |ws: warp::ws::Ws| {
-            let args = Arc::clone(&args);
-            ws.on_upgrade(|ws| {
-                handle(args, ws).map(|res| {
-                    if let Err(e) = res {
-                        warn!("Websocket client disconnected: {:?}", e);
-                    }
+pub async fn run(args: Backend, loader: Loader) -> Result<()> {
+    let state = Arc::new(Shared::new(State::new(&args)?));
+    let resolver = Arc::new(Resolver::new()?);
+    let limiter = Arc::new(Limiter::default());
+    let quotas = Arc::new(Quotas::load(args.quota_file.clone())?);
+    tokio::spawn(quota::persist(Arc::clone(&quotas)));
+
+    let bind = args.bind.clone();
+    let proxy_protocol = args.proxy_protocol;
+
+    {
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let path = loader.path();
+            let reload = || {
+                let args = match loader.load()?.subcommand {
+                    SubCommand::Backend(args) => args,
+                    _ => bail!("Unexpected subcommand"),
+                };
+                if args.bind != bind || args.proxy_protocol != proxy_protocol {
+                    warn!("Changing the bind address or proxy protocol requires a restart");
+                }
+                state.store(State::new(&args)?);
+                Ok(())
+            };
+            if let Err(err) = reload::watch(path, reload).await {
+                error!("Failed to watch for config changes: {:#}", err);
+            }
+        });
+    }
+
+    let routes = {
+        let quotas = Arc::clone(&quotas);
+        warp::path("connect")
+            .and(warp::ext::get::<Peer>())
+            .and(warp::header::headers_cloned())
+            .and(warp::ws())
+            .map(move |peer: Peer, headers: HeaderMap, ws: warp::ws::Ws| {
+                let state = state.load();
+                let resolver = Arc::clone(&resolver);
+                let limiter = Arc::clone(&limiter);
+                let quotas = Arc::clone(&quotas);
+                let client = forwarded::client_addr(
+                    peer.0.ip(),
+                    &headers,
+                    state.forwarded_header,
+                    &state.trusted_proxies,
+                    state.trust_unix_socket,
+                )
+                .map(|ip| ip.to_string())
+                .unwrap_or_else(|| "local".to_string());
+                ws.on_upgrade(|ws| {
+                    handle(state, resolver, limiter, quotas, client, ws).map(|res| {
+                        if let Err(e) = res {
+                            warn!("Websocket client disconnected: {:?}", e);
+                        }
+                    })
                 })
             })
+    };
+    let svc = warp::service(routes);
+
+    let mut listener = Listener::bind(&args.bind, args.socket_mode).await?;
+    info!("Started websocket server on {:?}", args.bind);
+
+    let mut interrupt =
+        signal(SignalKind::interrupt()).context("Failed to setup signal handler")?;
+    let mut terminate =
+        signal(SignalKind::terminate()).context("Failed to setup signal handler")?;
+    loop {
+        let (mut stream, mut addr) = tokio::select! {
+            res = listener.accept() => res?,
+            _ = interrupt.recv() => break,
+            _ = terminate.recv() => break,
+        };
+        debug!("Connection from {}", addr);
+        let svc = svc.clone();
+        tokio::spawn(async move {
+            if proxy_protocol {
+                let header = proxy_protocol::read_header(&mut stream);
+                match time::timeout(PROXY_HEADER_TIMEOUT, header).await {
+                    Ok(Ok(Some(src))) => {
+                        debug!("Connection from {} is proxied for {}", addr, src);
+                        addr = PeerAddr::Tcp(src);
+                    }
+                    Ok(Ok(None)) => (),
+                    Ok(Err(err)) => {
+                        warn!(
+                            "Failed to read proxy protocol header from {}: {:#}",
+                            addr, err
+                        );
+                        return;
+                    }
+                    Err(_) => {
+                        warn!("Timeout while reading proxy protocol header from {}", addr);
+                        return;
+                    }
+                }
+            }
+
+            let peer = Peer(addr);
+            let svc = service_fn(move |mut req: Request<Body>| {
+                req.extensions_mut().insert(peer);
+                svc.clone().call(req)
+            });
+            let conn = Http::new().serve_connection(stream, svc).with_upgrades();
+            if let Err(err) = conn.await {
+                debug!("Connection from {} failed: {:?}", addr, err);
+            }
         });
+    }
 
-    warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
-    Ok(())
+    info!("Shutting down");
+    // don't lose the traffic counted since the last save
+    quotas.save()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::noise::Initiator;
+    use structopt::StructOpt;
+
+    fn state(args: &[&str]) -> State {
+        let args = Backend::from_iter(std::iter::once("backend").chain(args.iter().copied()));
+        State::new(&args).unwrap()
+    }
+
+    #[test]
+    fn test_allowed_clients() {
+        let dir = std::env::temp_dir().join(format!("sde-backend-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("backend.key");
+        let backend = PrivateKey::generate().unwrap();
+        backend.save(&path).unwrap();
+
+        let allowed = PrivateKey::generate().unwrap();
+        let other = PrivateKey::generate().unwrap();
+        let state = state(&[
+            "--noise-key",
+            path.to_str().unwrap(),
+            "--allow-client",
+            &allowed.public().to_string(),
+        ]);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let (_, msg) = Initiator::new(Some(&allowed), &backend.public()).unwrap();
+        let (_, key, _) = noise::respond(&backend, &msg).unwrap();
+        assert!(state.is_allowed_client(&key));
+
+        let (_, msg) = Initiator::new(Some(&other), &backend.public()).unwrap();
+        let (_, key, _) = noise::respond(&backend, &msg).unwrap();
+        assert!(!state.is_allowed_client(&key));
+
+        let (_, msg) = Initiator::new(None, &backend.public()).unwrap();
+        let (_, key, _) = noise::respond(&backend, &msg).unwrap();
+        assert!(!state.is_allowed_client(&key));
+    }
+
+    #[test]
+    fn test_no_allowed_clients() {
+        let state = state(&[]);
+        let key = PrivateKey::generate().unwrap().public();
+        assert!(state.is_allowed_client(&key));
+    }
+
+    #[test]
+    fn test_rules_before_allow() {
+        let state = state(&["-R", "example.com:* -> block", "-A", "*"]);
+        assert!(!state.is_allowed("example.com:443"));
+        assert!(state.is_allowed("example.org:443"));
+    }
+
+    #[test]
+    fn test_rules_before_profile() {
+        let state = state(&["--profile", "signal", "-R", "*.signal.org:* -> block"]);
+        assert!(!state.is_allowed("cdn.signal.org:443"));
+        assert!(state.is_allowed("textsecure-service.whispersystems.org:443"));
+    }
+
+    #[test]
+    fn test_unmatched_is_blocked() {
+        let state = state(&["-A", "*.signal.org:443"]);
+        assert!(state.is_allowed("cdn.signal.org:443"));
+        assert!(!state.is_allowed("cdn.signal.org:80"));
+        assert!(!state.is_allowed("example.com:443"));
+        let empty = self::state(&[]);
+        assert!(!empty.is_allowed("example.com:443"));
+    }
 }
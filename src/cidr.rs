@@ -0,0 +1,182 @@
+use crate::errors::*;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+const fn v4(a: u8, b: u8, c: u8, d: u8, prefix: u8) -> Cidr {
+    Cidr {
+        addr: IpAddr::V4(Ipv4Addr::new(a, b, c, d)),
+        prefix,
+    }
+}
+
+const fn v6(a: u16, b: u16, prefix: u8) -> Cidr {
+    Cidr {
+        addr: IpAddr::V6(Ipv6Addr::new(a, b, 0, 0, 0, 0, 0, 0)),
+        prefix,
+    }
+}
+
+/// Ranges that should never be reachable through the backend, see RFC 6890
+const SPECIAL_PURPOSE: &[Cidr] = &[
+    v4(0, 0, 0, 0, 8),
+    v4(10, 0, 0, 0, 8),
+    v4(100, 64, 0, 0, 10),
+    v4(127, 0, 0, 0, 8),
+    v4(169, 254, 0, 0, 16),
+    v4(172, 16, 0, 0, 12),
+    v4(192, 0, 0, 0, 24),
+    v4(192, 0, 2, 0, 24),
+    v4(192, 88, 99, 0, 24),
+    v4(192, 168, 0, 0, 16),
+    v4(198, 18, 0, 0, 15),
+    v4(198, 51, 100, 0, 24),
+    v4(203, 0, 113, 0, 24),
+    v4(224, 0, 0, 0, 4),
+    v4(240, 0, 0, 0, 4),
+    // unspecified, loopback and ipv4-compatible
+    v6(0, 0, 96),
+    v6(0x64, 0xff9b, 96),
+    v6(0x100, 0, 64),
+    v6(0x2001, 0, 23),
+    v6(0x2001, 0xdb8, 32),
+    v6(0x2002, 0, 16),
+    v6(0xfc00, 0, 7),
+    v6(0xfe80, 0, 10),
+    v6(0xff00, 0, 8),
+];
+
+/// Check if the address is loopback, private, link-local or otherwise not
+/// meant to be reachable from the internet
+pub fn is_special_purpose(ip: &IpAddr) -> bool {
+    let ip = match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => *ip,
+        },
+        _ => *ip,
+    };
+    SPECIAL_PURPOSE.iter().any(|cidr| cidr.contains(&ip))
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = mask(32, self.prefix) as u32;
+                u32::from(net) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = mask(128, self.prefix);
+                u128::from(net) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+fn mask(bits: u8, prefix: u8) -> u128 {
+    if prefix == 0 {
+        0
+    } else {
+        (!0u128 << (bits - prefix)) & (!0u128 >> (128 - bits))
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Cidr> {
+        let (addr, prefix) = if let Some(idx) = s.find('/') {
+            let (addr, prefix) = (&s[..idx], &s[idx + 1..]);
+            let prefix = prefix
+                .parse()
+                .with_context(|| anyhow!("Invalid prefix length: {:?}", s))?;
+            (addr, Some(prefix))
+        } else {
+            (s, None)
+        };
+
+        let addr = addr
+            .parse::<IpAddr>()
+            .with_context(|| anyhow!("Invalid ip address: {:?}", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        if prefix > max {
+            bail!("Prefix length is too long: {:?}", s);
+        }
+
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_contains_v4() {
+        let cidr = "10.0.0.0/8".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(&ip("10.0.0.0")));
+        assert!(cidr.contains(&ip("10.255.255.255")));
+        assert!(!cidr.contains(&ip("11.0.0.0")));
+        assert!(!cidr.contains(&ip("::ffff:10.0.0.1")));
+
+        let cidr = "192.168.1.1".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(&ip("192.168.1.1")));
+        assert!(!cidr.contains(&ip("192.168.1.2")));
+
+        let cidr = "0.0.0.0/0".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(&ip("1.2.3.4")));
+        assert!(!cidr.contains(&ip("::1")));
+    }
+
+    #[test]
+    fn test_contains_v6() {
+        let cidr = "2001:db8::/32".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(&ip("2001:db8::1")));
+        assert!(cidr.contains(&ip("2001:db8:ffff::")));
+        assert!(!cidr.contains(&ip("2001:db9::")));
+        assert!(!cidr.contains(&ip("32.1.13.184")));
+
+        let cidr = "::/0".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(&ip("::1")));
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "10.0.0.1".parse::<Cidr>().unwrap().to_string(),
+            "10.0.0.1/32"
+        );
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/".parse::<Cidr>().is_err());
+        assert!("example.com/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_special_purpose() {
+        assert!(is_special_purpose(&ip("127.0.0.1")));
+        assert!(is_special_purpose(&ip("::ffff:192.168.1.1")));
+        assert!(is_special_purpose(&ip("fe80::1")));
+        assert!(!is_special_purpose(&ip("1.1.1.1")));
+        assert!(!is_special_purpose(&ip("2606:4700::1111")));
+    }
+}
@@ -1,15 +1,34 @@
 use crate::errors::*;
 use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// The highest protocol version we speak
+pub const PROTOCOL_VERSION: u32 = 1;
+/// The oldest protocol version we still speak, version 0 are releases that
+/// didn't send a version
+pub const MIN_PROTOCOL_VERSION: u32 = 0;
+/// Optional protocol features we support, none are defined yet
+pub const CAPABILITIES: &[&str] = &[];
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Hello {
+    /// The highest protocol version the client supports
+    #[serde(default)]
+    pub version: u32,
+    /// Optional features the client supports, unknown ones are ignored
+    #[serde(default)]
+    pub capabilities: Vec<String>,
     pub addr: String,
 }
 
 impl Hello {
     #[inline(always)]
     pub fn new<I: Into<String>>(addr: I) -> Hello {
-        Hello { addr: addr.into() }
+        Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+            addr: addr.into(),
+        }
     }
 
     pub fn parse(msg: &[u8]) -> Result<Hello> {
@@ -21,11 +40,75 @@ impl Hello {
         let msg = serde_json::to_vec(self)?;
         Ok(msg)
     }
+
+    /// Pick the highest protocol version and the capabilities both sides support
+    pub fn select(&self) -> std::result::Result<Selection, RejectReason> {
+        // always false until the minimum version is raised
+        #[allow(clippy::absurd_extreme_comparisons)]
+        if self.version < MIN_PROTOCOL_VERSION {
+            return Err(RejectReason::UnsupportedVersion);
+        }
+        let version = self.version.min(PROTOCOL_VERSION);
+        let capabilities = self
+            .capabilities
+            .iter()
+            .filter(|c| CAPABILITIES.contains(&c.as_str()))
+            .cloned()
+            .collect();
+        Ok(Selection {
+            version,
+            capabilities,
+        })
+    }
+}
+
+/// The protocol version and capabilities the backend picked for a connection
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Selection {
+    pub version: u32,
+    pub capabilities: Vec<String>,
+}
+
+impl Selection {
+    /// Make sure the backend picked something we actually support
+    pub fn verify(&self) -> Result<()> {
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&self.version) {
+            bail!(
+                "Backend selected protocol version {}, but only versions {} to {} are supported",
+                self.version,
+                MIN_PROTOCOL_VERSION,
+                PROTOCOL_VERSION
+            );
+        }
+        if let Some(c) = self
+            .capabilities
+            .iter()
+            .find(|c| !CAPABILITIES.contains(&c.as_str()))
+        {
+            bail!("Backend selected a capability we didn't offer: {:?}", c);
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Selection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "protocol {}", self.version)?;
+        if !self.capabilities.is_empty() {
+            write!(f, " ({})", self.capabilities.join(", "))?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum HelloResponse {
+    /// The connection is established, sent by and to releases without a
+    /// protocol version
     Accepted,
+    Rejected(RejectReason),
+    /// The connection is established with this protocol version and capabilities
+    Established(Selection),
 }
 
 impl HelloResponse {
@@ -39,3 +122,99 @@ impl HelloResponse {
         Ok(msg)
     }
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum RejectReason {
+    /// The destination is not allowed by the rules of the backend
+    NotAllowed,
+    /// The backend failed to resolve the destination
+    ResolveFailed,
+    /// The destination resolves to an address the backend refuses to connect to
+    ForbiddenAddress,
+    /// The backend failed to connect to the destination
+    ConnectFailed,
+    /// The client is opening new connections too quickly
+    RateLimited,
+    /// The client or the backend has too many active connections
+    TooManyConnections,
+    /// The client has used up its daily or monthly transfer quota
+    QuotaExceeded,
+    /// The client authenticated with a key the backend doesn't accept
+    Unauthorized,
+    /// The backend doesn't support the protocol version of the client anymore
+    UnsupportedVersion,
+    /// A reason that was added in a later release
+    #[serde(other)]
+    Unknown,
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            RejectReason::NotAllowed => "destination is not allowed",
+            RejectReason::ResolveFailed => "failed to resolve destination",
+            RejectReason::ForbiddenAddress => "destination resolves to a forbidden address",
+            RejectReason::ConnectFailed => "failed to connect to destination",
+            RejectReason::RateLimited => "too many new connections, slow down",
+            RejectReason::TooManyConnections => "too many active connections",
+            RejectReason::QuotaExceeded => "transfer quota exceeded",
+            RejectReason::Unauthorized => "client key is not allowed",
+            RejectReason::UnsupportedVersion => "protocol version is not supported, please update",
+            RejectReason::Unknown => "unknown reason, the backend might be newer than this client",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unknown_reject_reason() {
+        let msg = HelloResponse::parse(br#"{"Rejected":"SomethingNew"}"#).unwrap();
+        assert!(matches!(
+            msg,
+            HelloResponse::Rejected(RejectReason::Unknown)
+        ));
+        let msg = HelloResponse::parse(br#"{"Rejected":"QuotaExceeded"}"#).unwrap();
+        assert!(matches!(
+            msg,
+            HelloResponse::Rejected(RejectReason::QuotaExceeded)
+        ));
+    }
+
+    #[test]
+    fn test_old_client() {
+        let hello = Hello::parse(br#"{"addr":"example.com:443"}"#).unwrap();
+        assert_eq!(hello.version, 0);
+        let selection = hello.select().unwrap();
+        assert_eq!(selection.version, 0);
+        assert!(selection.capabilities.is_empty());
+    }
+
+    #[test]
+    fn test_newer_client() {
+        let hello =
+            Hello::parse(br#"{"version":99,"capabilities":["future"],"addr":"example.com:443"}"#)
+                .unwrap();
+        let selection = hello.select().unwrap();
+        assert_eq!(selection.version, PROTOCOL_VERSION);
+        assert!(selection.capabilities.is_empty());
+        selection.verify().unwrap();
+    }
+
+    #[test]
+    fn test_verify_selection() {
+        let selection = Selection {
+            version: PROTOCOL_VERSION + 1,
+            capabilities: Vec::new(),
+        };
+        assert!(selection.verify().is_err());
+        let selection = Selection {
+            version: PROTOCOL_VERSION,
+            capabilities: vec!["future".to_string()],
+        };
+        assert!(selection.verify().is_err());
+    }
+}
@@ -0,0 +1,681 @@
+use crate::args::{self, Args, SubCommand};
+use crate::cidr::Cidr;
+use crate::errors::*;
+use crate::listener;
+use crate::noise::PublicKey;
+use crate::rules::Rule;
+use crate::tls;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use structopt::clap::ArgMatches;
+use structopt::StructOpt;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub resolver_ip: Option<String>,
+    pub resolver_name: Option<String>,
+    pub proxy: ProxyConfig,
+    // an empty list would be emitted as a value after the [proxy] table
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub proxies: Vec<ProxyConfig>,
+    pub tunnel: TunnelConfig,
+    pub backend: BackendConfig,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    pub name: Option<String>,
+    pub addr: Option<String>,
+    pub port: Option<u16>,
+    pub skip_tls: Option<bool>,
+    pub ech: Option<String>,
+    pub ca_files: Vec<PathBuf>,
+    pub system_roots: Option<bool>,
+    pub pins: Vec<String>,
+    pub host: Option<String>,
+    pub path: Option<String>,
+    pub strategy: Option<String>,
+    pub failure_threshold: Option<u32>,
+    pub cooldown: Option<u64>,
+    pub probe_addr: Option<String>,
+    pub upstream: Option<String>,
+    pub backend_key: Option<String>,
+    pub identity: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TunnelConfig {
+    pub bind: Option<String>,
+    pub socket_mode: Option<String>,
+    pub probe_interval: Option<u64>,
+    pub direct_upstream: Option<bool>,
+    pub strict: Option<bool>,
+    pub learn: Option<PathBuf>,
+    pub allow_uids: Vec<u32>,
+    pub allow_gids: Vec<u32>,
+    pub forward: Vec<String>,
+    pub rules: Vec<String>,
+    pub profiles: Vec<String>,
+    pub upload_limit: Option<u64>,
+    pub download_limit: Option<u64>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackendConfig {
+    pub bind: Option<String>,
+    pub socket_mode: Option<String>,
+    pub trusted_proxies: Vec<String>,
+    pub forwarded_header: Option<String>,
+    pub trust_unix_socket: Option<bool>,
+    pub proxy_protocol: Option<bool>,
+    pub allow: Vec<String>,
+    pub rules: Vec<String>,
+    pub profiles: Vec<String>,
+    pub allow_cidrs: Vec<String>,
+    pub resolver: Option<String>,
+    pub max_sessions_per_client: Option<usize>,
+    pub max_connect_rate: Option<u64>,
+    pub max_connections: Option<usize>,
+    pub bandwidth_limit: Option<u64>,
+    pub client_bandwidth_limit: Option<u64>,
+    pub destination_bandwidth_limit: Option<u64>,
+    pub daily_quota: Option<u64>,
+    pub monthly_quota: Option<u64>,
+    pub quota_file: Option<PathBuf>,
+    pub noise_key: Option<PathBuf>,
+    pub allow_clients: Vec<String>,
+    pub ping_interval: Option<u64>,
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<ConfigFile> {
+        let buf = fs::read_to_string(path)
+            .with_context(|| anyhow!("Failed to read config file: {:?}", path))?;
+        let config = toml::from_str(&buf)
+            .with_context(|| anyhow!("Failed to parse config file: {:?}", path))?;
+        Ok(config)
+    }
+
+    /// Fill in everything that wasn't explicitly set on the command line,
+    /// lists from the config file are appended to the ones from the command line
+    pub fn apply(&self, args: &mut Args, m: &ArgMatches) -> Result<()> {
+        set(m, "resolver-ip", &mut args.resolver_ip, &self.resolver_ip);
+        set(
+            m,
+            "resolver-name",
+            &mut args.resolver_name,
+            &self.resolver_name,
+        );
+
+        match &mut args.subcommand {
+            SubCommand::Connect(args) => {
+                let m = m.subcommand_matches("connect").unwrap_or(m);
+                self.proxy.apply(&mut args.proxy, m)?;
+                args.proxy.proxies.extend(self.endpoints()?);
+            }
+            SubCommand::Ping(args) => {
+                let m = m.subcommand_matches("ping").unwrap_or(m);
+                self.proxy.apply(&mut args.proxy, m)?;
+                args.proxy.proxies.extend(self.endpoints()?);
+            }
+            SubCommand::Tunnel(args) => {
+                let m = m.subcommand_matches("tunnel").unwrap_or(m);
+                self.proxy.apply(&mut args.proxy, m)?;
+                args.proxy.proxies.extend(self.endpoints()?);
+                self.tunnel.apply(args, m)?;
+            }
+            SubCommand::Backend(args) => {
+                let m = m.subcommand_matches("backend").unwrap_or(m);
+                self.backend.apply(args, m)?;
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    /// The additional proxies from the `[[proxies]]` sections
+    pub fn endpoints(&self) -> Result<Vec<args::Endpoint>> {
+        self.proxies.iter().map(|p| p.to_endpoint()).collect()
+    }
+}
+
+impl ProxyConfig {
+    fn apply(&self, args: &mut args::Proxy, m: &ArgMatches) -> Result<()> {
+        set(m, "proxy-name", &mut args.proxy_name, &self.name);
+        set_opt(m, "proxy-addr", &mut args.proxy_addr, &self.addr);
+        set(m, "proxy-port", &mut args.proxy_port, &self.port);
+        set(m, "skip-tls", &mut args.skip_tls, &self.skip_tls);
+        if let Some(ech) = &self.ech {
+            if !explicit(m, "ech") {
+                args.ech = ech.parse()?;
+            }
+        }
+        args.ca_files.extend(self.ca_files.iter().cloned());
+        set(
+            m,
+            "system-roots",
+            &mut args.system_roots,
+            &self.system_roots,
+        );
+        args.pins.extend(self.pins.iter().cloned());
+        set_opt(m, "proxy-host", &mut args.proxy_host, &self.host);
+        set(m, "proxy-path", &mut args.proxy_path, &self.path);
+        if let Some(strategy) = &self.strategy {
+            if !explicit(m, "strategy") {
+                args.strategy = strategy.parse()?;
+            }
+        }
+        set(
+            m,
+            "failure-threshold",
+            &mut args.failure_threshold,
+            &self.failure_threshold,
+        );
+        set(m, "cooldown", &mut args.cooldown, &self.cooldown);
+        set(m, "probe-addr", &mut args.probe_addr, &self.probe_addr);
+        if let Some(upstream) = &self.upstream {
+            if !explicit(m, "upstream") {
+                args.upstream = Some(upstream.parse()?);
+            }
+        }
+        if let Some(key) = &self.backend_key {
+            if !explicit(m, "backend-key") {
+                args.backend_key = Some(key.parse()?);
+            }
+        }
+        set_opt(m, "identity", &mut args.identity, &self.identity);
+        Ok(())
+    }
+
+    fn to_endpoint(&self) -> Result<args::Endpoint> {
+        let name = self
+            .name
+            .clone()
+            .ok_or_else(|| anyhow!("Every [[proxies]] entry needs a name"))?;
+        if self.addr.is_none() {
+            bail!("Proxy {:?} has no addr", name);
+        }
+        if self.strategy.is_some()
+            || self.failure_threshold.is_some()
+            || self.cooldown.is_some()
+            || self.probe_addr.is_some()
+        {
+            bail!(
+                "strategy, failure_threshold, cooldown and probe_addr can only be set in [proxy]"
+            );
+        }
+
+        // start with the defaults, env variables don't apply here
+        let m = args::Proxy::clap().get_matches_from_safe(["proxy"])?;
+        let mut proxy = args::Proxy::from_clap(&m);
+        proxy.proxy_addr = None;
+        self.apply(&mut proxy, &ArgMatches::default())?;
+        Ok(args::Endpoint { name, proxy })
+    }
+
+    fn from_args(args: &args::Proxy) -> ProxyConfig {
+        ProxyConfig {
+            name: Some(args.proxy_name.clone()),
+            addr: args.proxy_addr.clone(),
+            port: Some(args.proxy_port),
+            skip_tls: Some(args.skip_tls),
+            ech: Some(args.ech.to_string()),
+            ca_files: args.ca_files.clone(),
+            system_roots: Some(args.system_roots),
+            pins: args.pins.clone(),
+            host: args.proxy_host.clone(),
+            path: Some(args.proxy_path.clone()),
+            strategy: Some(args.strategy.to_string()),
+            failure_threshold: Some(args.failure_threshold),
+            cooldown: Some(args.cooldown),
+            probe_addr: Some(args.probe_addr.clone()),
+            upstream: args.upstream.as_ref().map(|u| u.as_raw().to_string()),
+            backend_key: args.backend_key.map(|k| k.to_string()),
+            identity: args.identity.clone(),
+        }
+    }
+
+    fn from_endpoint(endpoint: &args::Endpoint) -> ProxyConfig {
+        ProxyConfig {
+            name: Some(endpoint.name.clone()),
+            strategy: None,
+            failure_threshold: None,
+            cooldown: None,
+            probe_addr: None,
+            ..ProxyConfig::from_args(&endpoint.proxy)
+        }
+    }
+}
+
+impl TunnelConfig {
+    fn apply(&self, args: &mut args::Tunnel, m: &ArgMatches) -> Result<()> {
+        set(m, "bind", &mut args.bind, &self.bind);
+        set_mode(m, &mut args.socket_mode, &self.socket_mode)?;
+        set_opt(
+            m,
+            "probe-interval",
+            &mut args.probe_interval,
+            &self.probe_interval,
+        );
+        set(
+            m,
+            "direct-upstream",
+            &mut args.direct_upstream,
+            &self.direct_upstream,
+        );
+        set(m, "strict", &mut args.strict, &self.strict);
+        set_opt(m, "learn", &mut args.learn, &self.learn);
+        args.allowed_uids.extend(self.allow_uids.iter().copied());
+        args.allowed_gids.extend(self.allow_gids.iter().copied());
+        args.forward.extend(parse_all(&self.forward)?);
+        args.rules.extend(parse_all(&self.rules)?);
+        args.profiles.extend(self.profiles.iter().cloned());
+        set_opt(
+            m,
+            "upload-limit",
+            &mut args.upload_limit,
+            &self.upload_limit,
+        );
+        set_opt(
+            m,
+            "download-limit",
+            &mut args.download_limit,
+            &self.download_limit,
+        );
+        Ok(())
+    }
+
+    fn from_args(args: &args::Tunnel) -> TunnelConfig {
+        TunnelConfig {
+            bind: Some(args.bind.clone()),
+            socket_mode: args.socket_mode.map(|mode| format!("{:o}", mode)),
+            probe_interval: args.probe_interval,
+            direct_upstream: Some(args.direct_upstream),
+            strict: Some(args.strict),
+            learn: args.learn.clone(),
+            allow_uids: args.allowed_uids.clone(),
+            allow_gids: args.allowed_gids.clone(),
+            forward: to_strings(&args.forward),
+            rules: args.rules.iter().map(Rule::to_raw).collect(),
+            profiles: args.profiles.clone(),
+            upload_limit: args.upload_limit,
+            download_limit: args.download_limit,
+        }
+    }
+}
+
+impl BackendConfig {
+    fn apply(&self, args: &mut args::Backend, m: &ArgMatches) -> Result<()> {
+        set(m, "bind", &mut args.bind, &self.bind);
+        set_mode(m, &mut args.socket_mode, &self.socket_mode)?;
+        args.trusted_proxies
+            .extend(parse_all::<Cidr>(&self.trusted_proxies)?);
+        if let Some(header) = &self.forwarded_header {
+            if !explicit(m, "forwarded-header") {
+                args.forwarded_header = header.parse()?;
+            }
+        }
+        set(
+            m,
+            "trust-unix-socket",
+            &mut args.trust_unix_socket,
+            &self.trust_unix_socket,
+        );
+        set(
+            m,
+            "proxy-protocol",
+            &mut args.proxy_protocol,
+            &self.proxy_protocol,
+        );
+        args.allowed.extend(parse_all(&self.allow)?);
+        args.rules.extend(parse_all(&self.rules)?);
+        args.profiles.extend(self.profiles.iter().cloned());
+        args.allowed_cidrs
+            .extend(parse_all::<Cidr>(&self.allow_cidrs)?);
+        if let Some(resolver) = &self.resolver {
+            if !explicit(m, "resolver") {
+                args.resolver = resolver.parse()?;
+            }
+        }
+        set_opt(
+            m,
+            "max-sessions-per-client",
+            &mut args.max_sessions_per_client,
+            &self.max_sessions_per_client,
+        );
+        set_opt(
+            m,
+            "max-connect-rate",
+            &mut args.max_connect_rate,
+            &self.max_connect_rate,
+        );
+        set_opt(
+            m,
+            "max-connections",
+            &mut args.max_connections,
+            &self.max_connections,
+        );
+        set_opt(
+            m,
+            "bandwidth-limit",
+            &mut args.bandwidth_limit,
+            &self.bandwidth_limit,
+        );
+        set_opt(
+            m,
+            "client-bandwidth-limit",
+            &mut args.client_bandwidth_limit,
+            &self.client_bandwidth_limit,
+        );
+        set_opt(
+            m,
+            "destination-bandwidth-limit",
+            &mut args.destination_bandwidth_limit,
+            &self.destination_bandwidth_limit,
+        );
+        set_opt(m, "daily-quota", &mut args.daily_quota, &self.daily_quota);
+        set_opt(
+            m,
+            "monthly-quota",
+            &mut args.monthly_quota,
+            &self.monthly_quota,
+        );
+        set_opt(m, "quota-file", &mut args.quota_file, &self.quota_file);
+        set_opt(m, "noise-key", &mut args.noise_key, &self.noise_key);
+        args.allowed_clients
+            .extend(parse_all::<PublicKey>(&self.allow_clients)?);
+        set_opt(
+            m,
+            "ping-interval",
+            &mut args.ping_interval,
+            &self.ping_interval,
+        );
+        Ok(())
+    }
+
+    fn from_args(args: &args::Backend) -> BackendConfig {
+        BackendConfig {
+            bind: Some(args.bind.clone()),
+            socket_mode: args.socket_mode.map(|mode| format!("{:o}", mode)),
+            trusted_proxies: to_strings(&args.trusted_proxies),
+            forwarded_header: Some(args.forwarded_header.to_string()),
+            trust_unix_socket: Some(args.trust_unix_socket),
+            proxy_protocol: Some(args.proxy_protocol),
+            allow: to_strings(&args.allowed),
+            rules: args.rules.iter().map(Rule::to_raw).collect(),
+            profiles: args.profiles.clone(),
+            allow_cidrs: to_strings(&args.allowed_cidrs),
+            resolver: Some(args.resolver.to_string()),
+            max_sessions_per_client: args.max_sessions_per_client,
+            max_connect_rate: args.max_connect_rate,
+            max_connections: args.max_connections,
+            bandwidth_limit: args.bandwidth_limit,
+            client_bandwidth_limit: args.client_bandwidth_limit,
+            destination_bandwidth_limit: args.destination_bandwidth_limit,
+            daily_quota: args.daily_quota,
+            monthly_quota: args.monthly_quota,
+            quota_file: args.quota_file.clone(),
+            noise_key: args.noise_key.clone(),
+            allow_clients: to_strings(&args.allowed_clients),
+            ping_interval: args.ping_interval,
+        }
+    }
+}
+
+/// Keeps the command line arguments around so the config file can be re-applied on reload
+#[derive(Debug, Clone)]
+pub struct Loader {
+    args: Args,
+    matches: ArgMatches<'static>,
+}
+
+impl Loader {
+    pub fn new(args: Args, matches: ArgMatches<'static>) -> Loader {
+        Loader { args, matches }
+    }
+
+    pub fn path(&self) -> Option<PathBuf> {
+        self.args.config.clone()
+    }
+
+    /// The command line arguments with the config file applied
+    pub fn load(&self) -> Result<Args> {
+        let mut args = self.args.clone();
+        if let Some(path) = &args.config {
+            let config = ConfigFile::load(path)?;
+            config.apply(&mut args, &self.matches)?;
+        }
+        Ok(args)
+    }
+}
+
+/// The environment variable an option can be set with
+fn env_var(name: &str) -> Option<&'static str> {
+    match name {
+        "config" => Some(args::ENV_CONFIG),
+        "resolver-ip" => Some(args::ENV_RESOLVER_IP),
+        "resolver-name" => Some(args::ENV_RESOLVER_NAME),
+        "proxy-addr" => Some(args::ENV_PROXY),
+        _ => None,
+    }
+}
+
+/// If an option was given on the command line or in the environment, these
+/// take precedence over the config file
+fn explicit(m: &ArgMatches, name: &str) -> bool {
+    if m.occurrences_of(name) > 0 {
+        return true;
+    }
+    // clap doesn't count values from the environment as occurrences
+    match (env_var(name), m.value_of_os(name)) {
+        (Some(var), Some(value)) => env::var_os(var).as_deref() == Some(value),
+        _ => false,
+    }
+}
+
+fn set<T: Clone>(m: &ArgMatches, name: &str, target: &mut T, value: &Option<T>) {
+    if let Some(value) = value {
+        if !explicit(m, name) {
+            *target = value.clone();
+        }
+    }
+}
+
+fn set_opt<T: Clone>(m: &ArgMatches, name: &str, target: &mut Option<T>, value: &Option<T>) {
+    if value.is_some() && !explicit(m, name) {
+        *target = value.clone();
+    }
+}
+
+fn set_mode(m: &ArgMatches, target: &mut Option<u32>, value: &Option<String>) -> Result<()> {
+    if let Some(value) = value {
+        if !explicit(m, "socket-mode") {
+            *target = Some(listener::parse_mode(value)?);
+        }
+    }
+    Ok(())
+}
+
+fn parse_all<T>(list: &[String]) -> Result<Vec<T>>
+where
+    T: std::str::FromStr<Err = Error>,
+{
+    list.iter().map(|x| x.parse()).collect()
+}
+
+fn to_strings<T: ToString>(list: &[T]) -> Vec<String> {
+    list.iter().map(|x| x.to_string()).collect()
+}
+
+/// Validate the config file and print the effective configuration, the
+/// global arguments are expected to have the config file applied already
+pub fn check(args: &Args) -> Result<()> {
+    let path = args
+        .config
+        .as_ref()
+        .ok_or_else(|| anyhow!("No config file given, use --config"))?;
+    let file = ConfigFile::load(path)?;
+
+    let m = args::Tunnel::clap().get_matches_from_safe(["tunnel"])?;
+    let mut tunnel = args::Tunnel::from_clap(&m);
+    file.proxy.apply(&mut tunnel.proxy, &m)?;
+    file.tunnel.apply(&mut tunnel, &m)?;
+    tunnel.proxy.proxies = file.endpoints()?;
+    if tunnel.proxy.proxy_addr.is_some() || !tunnel.proxy.proxies.is_empty() {
+        for endpoint in tunnel.proxy.endpoints()? {
+            tls::client_config(&endpoint.proxy)
+                .with_context(|| anyhow!("Invalid configuration of proxy {:?}", endpoint.name))?;
+        }
+    }
+    tunnel.rules().context("Invalid tunnel configuration")?;
+
+    let m = args::Backend::clap().get_matches_from_safe(["backend"])?;
+    let mut backend = args::Backend::from_clap(&m);
+    file.backend.apply(&mut backend, &m)?;
+    backend.rules().context("Invalid backend configuration")?;
+    backend
+        .noise_key()
+        .context("Invalid backend configuration")?;
+
+    let effective = ConfigFile {
+        resolver_ip: Some(args.resolver_ip.clone()),
+        resolver_name: Some(args.resolver_name.clone()),
+        proxy: ProxyConfig::from_args(&tunnel.proxy),
+        proxies: tunnel
+            .proxy
+            .proxies
+            .iter()
+            .map(ProxyConfig::from_endpoint)
+            .collect(),
+        tunnel: TunnelConfig::from_args(&tunnel),
+        backend: BackendConfig::from_args(&backend),
+    };
+    print!("{}", toml::to_string(&effective)?);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(argv: &[&str], config: &str) -> Args {
+        let m = Args::clap()
+            .get_matches_from_safe(std::iter::once("signal-doh-ech").chain(argv.iter().copied()))
+            .unwrap();
+        let mut args = Args::from_clap(&m);
+        let config: ConfigFile = toml::from_str(config).unwrap();
+        config.apply(&mut args, &m).unwrap();
+        args
+    }
+
+    fn tunnel(args: Args) -> args::Tunnel {
+        match args.subcommand {
+            SubCommand::Tunnel(tunnel) => tunnel,
+            _ => panic!("not a tunnel"),
+        }
+    }
+
+    const CONFIG: &str = r#"
+        resolver_ip = "9.9.9.9"
+
+        [proxy]
+        addr = "file.example.com"
+        port = 8443
+
+        [tunnel]
+        bind = "127.0.0.1:2000"
+        strict = true
+        forward = ["file.example.com:443"]
+        rules = ["blocked.example.com:* -> block"]
+        "#;
+
+    #[test]
+    fn test_file_fills_in_defaults() {
+        let args = load(&["tunnel"], CONFIG);
+        assert_eq!(args.resolver_ip, "9.9.9.9");
+        let tunnel = tunnel(args);
+        assert_eq!(tunnel.proxy.proxy_port, 8443);
+        assert_eq!(tunnel.bind, "127.0.0.1:2000");
+        assert!(tunnel.strict);
+    }
+
+    #[test]
+    fn test_cli_overrides_file() {
+        let args = load(
+            &[
+                "--resolver-ip",
+                "1.0.0.1",
+                "tunnel",
+                "--proxy-port",
+                "443",
+                "--bind",
+                "127.0.0.1:3000",
+            ],
+            CONFIG,
+        );
+        assert_eq!(args.resolver_ip, "1.0.0.1");
+        let tunnel = tunnel(args);
+        assert_eq!(tunnel.proxy.proxy_port, 443);
+        assert_eq!(tunnel.bind, "127.0.0.1:3000");
+        // not given on the command line, still taken from the file
+        assert!(tunnel.strict);
+    }
+
+    #[test]
+    fn test_lists_are_appended() {
+        let args = load(
+            &[
+                "tunnel",
+                "-F",
+                "cli.example.com:443",
+                "-R",
+                "*:80 -> direct",
+            ],
+            CONFIG,
+        );
+        let tunnel = tunnel(args);
+        assert_eq!(
+            to_strings(&tunnel.forward),
+            &["cli.example.com:443", "file.example.com:443"]
+        );
+        assert_eq!(
+            tunnel.rules.iter().map(Rule::to_raw).collect::<Vec<_>>(),
+            &["*:80 -> direct", "blocked.example.com:* -> block"]
+        );
+    }
+
+    #[test]
+    fn test_env_overrides_file() {
+        // the other tests don't look at the resolver name, setting it is safe
+        env::set_var(args::ENV_RESOLVER_NAME, "env.example.com");
+        let from_env = load(&["tunnel"], r#"resolver_name = "file.example.com""#);
+        let from_cli = load(
+            &["--resolver-name", "cli.example.com", "tunnel"],
+            r#"resolver_name = "file.example.com""#,
+        );
+        env::remove_var(args::ENV_RESOLVER_NAME);
+        let from_file = load(&["tunnel"], r#"resolver_name = "file.example.com""#);
+
+        assert_eq!(from_env.resolver_name, "env.example.com");
+        assert_eq!(from_cli.resolver_name, "cli.example.com");
+        assert_eq!(from_file.resolver_name, "file.example.com");
+    }
+
+    #[test]
+    fn test_explicit() {
+        let m = args::Tunnel::clap()
+            .get_matches_from_safe(["tunnel", "--bind", "127.0.0.1:1"])
+            .unwrap();
+        assert!(explicit(&m, "bind"));
+        assert!(!explicit(&m, "strict"));
+        assert!(!explicit(&ArgMatches::default(), "proxy-addr"));
+    }
+}
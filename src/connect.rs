@@ -1,27 +1,36 @@
-use crate::args::{Connect, Proxy};
+use crate::args::{Connect, EchMode, Proxy};
 use crate::common::{Hello, HelloResponse};
 use crate::dns;
 use crate::errors::*;
+use crate::limits::Throttles;
+use crate::noise::{Initiator, PrivateKey, Session};
+use crate::pool::Health;
+use crate::tls;
 use async_tungstenite::tokio::TokioAdapter;
 use async_tungstenite::tungstenite::Message;
 use async_tungstenite::WebSocketStream;
 use futures::task::{self, Poll};
 use futures::{select, FutureExt, SinkExt, StreamExt};
 use http::Request;
-use rustls::ClientConfig;
 use std::io;
 use std::marker::Unpin;
 use std::net::IpAddr;
 use std::net::SocketAddr;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, Once};
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::time;
 use tokio_rustls::client::TlsStream;
 use tokio_rustls::TlsConnector;
 use webpki::DNSNameRef;
 
-async fn connect(ips: &[IpAddr], port: u16) -> Result<TcpStream> {
+/// A proxy that doesn't finish the handshake in time counts as failed, so
+/// the next one is tried instead of stalling the connection
+const OPEN_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub async fn connect(ips: &[IpAddr], port: u16) -> Result<TcpStream> {
     debug!("Trying all of: {:?}", ips);
     for ip in ips {
         let addr = SocketAddr::new(*ip, port);
@@ -45,13 +54,36 @@ pub async fn connect_dns(proxy: &str, port: u16) -> Result<TcpStream> {
     connect(&ips, port).await
 }
 
-async fn setup_tls(stream: TcpStream, proxy: &str) -> Result<TlsStream<TcpStream>> {
-    info!("Negotiating tls connection ({:?})", proxy);
+// rustls doesn't support ECH yet, so there's never an ECHConfig available and
+// this only logs that we continue without it
+fn ech_fallback(args: &Proxy) -> Result<()> {
+    let proxy = args.addr()?;
+    match args.ech {
+        EchMode::Prefer => {
+            // this happens for every connection, only warn about it once
+            static WARNED: Once = Once::new();
+            WARNED.call_once(|| {
+                warn!(
+                    "No ECHConfig available for {:?}, falling back to plain sni",
+                    proxy
+                )
+            });
+            debug!("No ECHConfig available for {:?}, using plain sni", proxy);
+        }
+        EchMode::None => debug!("ECH is disabled, using plain sni for {:?}", proxy),
+    }
+    Ok(())
+}
+
+async fn setup_tls(stream: TcpStream, args: &Proxy) -> Result<TlsStream<TcpStream>> {
+    let proxy = args.addr()?;
+    info!(
+        "Negotiating tls connection ({:?}, ech={:?})",
+        proxy, args.ech
+    );
+    ech_fallback(args)?;
 
-    let mut config = ClientConfig::new();
-    config
-        .root_store
-        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    let config = tls::client_config(args)?;
     let config = TlsConnector::from(Arc::new(config));
     let dnsname = DNSNameRef::try_from_ascii_str(proxy)?;
 
@@ -61,9 +93,9 @@ async fn setup_tls(stream: TcpStream, proxy: &str) -> Result<TlsStream<TcpStream
 
 async fn setup_ws<T: AsyncRead + AsyncWrite + Unpin>(
     stream: T,
-    proxy: &str,
+    args: &Proxy,
 ) -> Result<WebSocketStream<async_tungstenite::tokio::TokioAdapter<T>>> {
-    let url = format!("ws://{}/connect", proxy);
+    let url = format!("ws://{}{}", args.host()?, args.proxy_path);
     info!("Establishing websocket with {:?}", url);
     let req = Request::get(url).body(()).unwrap();
 
@@ -74,29 +106,211 @@ async fn setup_ws<T: AsyncRead + AsyncWrite + Unpin>(
     Ok(sock)
 }
 
-async fn req_proxy<T: AsyncRead + AsyncWrite + Unpin>(
-    sock: &mut WebSocketStream<TokioAdapter<T>>,
-    dest: &str,
-) -> Result<()> {
+/// Send the hello and wait for the response of the proxy
+pub async fn hello(conn: &mut Connection, dest: &str) -> Result<HelloResponse> {
     let hello = Hello::new(dest);
     debug!("Sending hello: {:?}", hello);
-    let hello = serde_json::to_vec(&hello)?;
-    sock.send(Message::binary(hello)).await?;
+    conn.send(&hello.to_vec()?).await?;
+
+    let msg = conn
+        .recv()
+        .await?
+        .ok_or_else(|| anyhow!("No hello response received"))?;
+    HelloResponse::parse(&msg)
+}
+
+async fn req_proxy(conn: &mut Connection, dest: &str) -> Result<()> {
+    match hello(conn, dest).await? {
+        HelloResponse::Accepted => debug!("Proxy doesn't support protocol versions"),
+        HelloResponse::Established(selection) => {
+            selection.verify()?;
+            debug!("Proxy selected {}", selection);
+        }
+        HelloResponse::Rejected(reason) => bail!("Proxy rejected connection: {}", reason),
+    }
+
+    info!("Connected");
+
+    Ok(())
+}
+
+/// The connection to the proxy, with or without tls
+pub enum ProxyStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for ProxyStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut *self {
+            ProxyStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            ProxyStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ProxyStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut *self {
+            ProxyStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            ProxyStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<io::Result<()>> {
+        match &mut *self {
+            ProxyStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            ProxyStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<io::Result<()>> {
+        match &mut *self {
+            ProxyStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            ProxyStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+pub type WsStream = WebSocketStream<TokioAdapter<ProxyStream>>;
 
-    let msg = sock
+/// The websocket to the proxy and the encryption of the channel inside of it
+pub struct Connection {
+    ws: WsStream,
+    session: Session,
+}
+
+impl Connection {
+    async fn send(&mut self, msg: &[u8]) -> Result<()> {
+        let msg = self.session.encrypt(msg)?;
+        self.ws.send(Message::binary(msg)).await?;
+        Ok(())
+    }
+
+    /// Receive the next binary message, None if the websocket was closed
+    async fn recv(&mut self) -> Result<Option<Vec<u8>>> {
+        while let Some(msg) = self.ws.next().await {
+            match msg? {
+                Message::Binary(msg) => return Ok(Some(self.session.decrypt(&msg)?)),
+                Message::Close(_) => break,
+                msg => trace!("Ignoring websocket pkt: {:?}", msg),
+            }
+        }
+        Ok(None)
+    }
+
+    pub async fn close(mut self) {
+        self.ws.close(None).await.ok();
+    }
+}
+
+/// Authenticate the backend and setup the encryption inside the websocket
+async fn setup_noise(ws: &mut WsStream, args: &Proxy) -> Result<Session> {
+    let key = match &args.backend_key {
+        Some(key) => key,
+        None => return Ok(Session::Plain),
+    };
+    let identity = match &args.identity {
+        Some(path) => Some(PrivateKey::load(path)?),
+        None => None,
+    };
+
+    debug!("Starting noise handshake with backend key {}", key);
+    let (initiator, msg) = Initiator::new(identity.as_ref(), key)?;
+    ws.send(Message::binary(msg)).await?;
+    let msg = ws
         .next()
         .await
-        .ok_or_else(|| anyhow!("No hello response received"))?
-        .context("Failed to read hello response")?;
+        .ok_or_else(|| anyhow!("No handshake response received"))?
+        .context("Failed to read handshake response")?;
     if let Message::Binary(msg) = msg {
-        let _msg = HelloResponse::parse(&msg)?;
+        // the backend rejects some connections before the handshake
+        if let Ok(HelloResponse::Rejected(reason)) = HelloResponse::parse(&msg) {
+            bail!("Proxy rejected connection: {}", reason);
+        }
+        initiator.finish(&msg)
     } else {
         bail!("Unexpected websocket pkt: {:?}", msg);
     }
+}
 
-    info!("Connected");
+/// Connect to a single proxy and setup the websocket
+pub async fn open(args: &Proxy) -> Result<Connection> {
+    let run = async {
+        let proxy = args.addr()?;
+        let stream = match &args.upstream {
+            Some(upstream) => upstream.connect(proxy, args.proxy_port).await?,
+            None => connect_dns(proxy, args.proxy_port).await?,
+        };
 
-    Ok(())
+        let stream = if args.skip_tls {
+            ProxyStream::Plain(stream)
+        } else {
+            let stream = setup_tls(stream, args)
+                .await
+                .context("Failed to setup tls connection")?;
+            ProxyStream::Tls(Box::new(stream))
+        };
+
+        let mut ws = setup_ws(stream, args)
+            .await
+            .context("Failed to setup websocket")?;
+        let session = setup_noise(&mut ws, args)
+            .await
+            .context("Failed to setup encryption")?;
+        Ok(Connection { ws, session })
+    };
+    time::timeout(OPEN_TIMEOUT, run)
+        .await
+        .map_err(|_| anyhow!("Timeout while connecting to proxy"))?
+}
+
+/// Open a websocket to the first proxy that works and request `addr`. Once
+/// the websocket is established the proxy counts as healthy, a rejection of
+/// the destination doesn't cause a failover. If `via` is set only the proxy
+/// with this name is used.
+pub async fn establish(
+    args: &Proxy,
+    health: &Health,
+    via: Option<&str>,
+    addr: &str,
+) -> Result<Connection> {
+    let mut endpoints = args.endpoints()?;
+    if let Some(name) = via {
+        endpoints.retain(|e| e.name == name);
+        if endpoints.is_empty() {
+            bail!("Unknown proxy: {:?}", name);
+        }
+    }
+    let mut last_err = None;
+
+    for endpoint in health.candidates(args, &endpoints) {
+        debug!("Trying proxy {:?}", endpoint.name);
+        let start = Instant::now();
+        match open(&endpoint.proxy).await {
+            Ok(mut conn) => {
+                health.success(&endpoint.name, start.elapsed());
+                info!("Using proxy {:?}", endpoint.name);
+                req_proxy(&mut conn, addr).await?;
+                return Ok(conn);
+            }
+            Err(err) => {
+                warn!("Failed to connect to proxy {:?}: {:#}", endpoint.name, err);
+                health.failure(args, &endpoint.name);
+                last_err = Some(err);
+            }
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| anyhow!("No proxy available"))).context("Every proxy failed")
 }
 
 pub struct Stdio {
@@ -140,9 +354,10 @@ impl AsyncWrite for Stdio {
     }
 }
 
-async fn relay<A: AsyncRead + AsyncWrite + Unpin, B: AsyncRead + AsyncWrite + Unpin>(
-    mut ws: WebSocketStream<TokioAdapter<A>>,
-    mut stream: B,
+async fn relay<T: AsyncRead + AsyncWrite + Unpin>(
+    mut conn: Connection,
+    mut stream: T,
+    throttles: &Throttles,
 ) -> Result<()> {
     let mut buf = [0u8; 4096];
 
@@ -156,13 +371,16 @@ async fn relay<A: AsyncRead + AsyncWrite + Unpin, B: AsyncRead + AsyncWrite + Un
                 }
                 let msg = &buf[..n];
                 trace!("Send: {:?}", msg);
-                ws.send(Message::binary(msg)).await?;
+                throttles.upload.consume(n).await;
+                conn.send(msg).await?;
             },
-            msg = ws.next().fuse() => {
+            msg = conn.ws.next().fuse() => {
                 trace!("Recv: {:?}", msg);
                 match msg {
                     Some(Ok(msg)) => {
                         if let Message::Binary(buf) = msg {
+                            let buf = conn.session.decrypt(&buf)?;
+                            throttles.download.consume(buf.len()).await;
                             stream.write_all(&buf).await?;
                         }
                     },
@@ -179,37 +397,31 @@ async fn relay<A: AsyncRead + AsyncWrite + Unpin, B: AsyncRead + AsyncWrite + Un
         };
     }
     debug!("Closing connection");
-    ws.close(None).await.ok();
+    conn.close().await;
 
     Ok(())
 }
 
 pub async fn run_with<T: AsyncRead + AsyncWrite + Unpin>(
     args: &Proxy,
+    health: &Health,
+    via: Option<&str>,
     addr: &str,
     local: T,
+    throttles: &Throttles,
 ) -> Result<()> {
-    let stream = connect_dns(&args.proxy_addr, args.proxy_port).await?;
-
-    let proxy = &args.proxy_addr;
-    if args.skip_tls {
-        let mut stream = setup_ws(stream, proxy)
-            .await
-            .context("Failed to setup websocket")?;
-        req_proxy(&mut stream, &addr).await?;
-        relay(stream, local).await
-    } else {
-        let stream = setup_tls(stream, proxy)
-            .await
-            .context("Failed to setup tls connection")?;
-        let mut stream = setup_ws(stream, proxy)
-            .await
-            .context("Failed to setup websocket")?;
-        req_proxy(&mut stream, &addr).await?;
-        relay(stream, local).await
-    }
+    let stream = establish(args, health, via, addr).await?;
+    relay(stream, local, throttles).await
 }
 
 pub async fn run(args: Connect) -> Result<()> {
-    run_with(&args.proxy, &args.addr, Stdio::default()).await
+    run_with(
+        &args.proxy,
+        &Health::default(),
+        None,
+        &args.addr,
+        Stdio::default(),
+        &Throttles::default(),
+    )
+    .await
 }
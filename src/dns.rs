@@ -1,39 +1,85 @@
 use crate::args::Resolve;
 use crate::errors::*;
 use doh_dns::{client::HyperDnsClient, Dns, DnsHttpsServer};
+use std::collections::HashMap;
 use std::net::IpAddr;
-use std::time::Duration;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-pub async fn resolve(name: &str) -> Result<Vec<IpAddr>> {
-    // TODO: this shouldn't be hardcoded
-    let dns: Dns<HyperDnsClient> = Dns::with_servers(&[
-        DnsHttpsServer::Google(Duration::from_secs(2)),
-        DnsHttpsServer::Cloudflare1_1_1_1(Duration::from_secs(10)),
-    ])?;
-
-    info!("Resolving {:?}", name);
-    // TODO: this should resolve ipv4+ipv6 at the same time
-    let responses = dns.resolve_a(name).await?;
-    if responses.is_empty() {
-        bail!("No entries found.")
-    }
+const MIN_TTL: u64 = 10;
+const MAX_TTL: u64 = 300;
+
+pub struct Resolver {
+    dns: Dns<HyperDnsClient>,
+    cache: Mutex<HashMap<String, (Instant, Vec<IpAddr>)>>,
+}
 
-    let addrs = responses
-        .iter()
-        .flat_map(|res| {
-            debug!(
-                "Got dns record: {:?} (type={})",
-                res,
-                dns.rtype_to_name(res.r#type)
-            );
-            match res.r#type {
-                1 | 28 => res.data.parse().ok(),
-                _ => None,
-            }
+impl Resolver {
+    pub fn new() -> Result<Resolver> {
+        // TODO: this shouldn't be hardcoded
+        let dns = Dns::with_servers(&[
+            DnsHttpsServer::Google(Duration::from_secs(2)),
+            DnsHttpsServer::Cloudflare1_1_1_1(Duration::from_secs(10)),
+        ])?;
+        Ok(Resolver {
+            dns,
+            cache: Mutex::new(HashMap::new()),
         })
-        .collect();
+    }
+
+    fn cached(&self, name: &str) -> Option<Vec<IpAddr>> {
+        let mut cache = self.cache.lock().unwrap();
+        let now = Instant::now();
+        cache.retain(|_, (expires, _)| *expires > now);
+        cache.get(name).map(|(_, addrs)| addrs.clone())
+    }
+
+    pub async fn resolve(&self, name: &str) -> Result<Vec<IpAddr>> {
+        if let Some(addrs) = self.cached(name) {
+            debug!("Using cached dns records for {:?}: {:?}", name, addrs);
+            return Ok(addrs);
+        }
 
-    Ok(addrs)
+        info!("Resolving {:?}", name);
+        // TODO: this should resolve ipv4+ipv6 at the same time
+        let responses = self.dns.resolve_a(name).await?;
+        if responses.is_empty() {
+            bail!("No entries found.")
+        }
+
+        let mut ttl = MAX_TTL;
+        let addrs = responses
+            .iter()
+            .flat_map(|res| {
+                debug!(
+                    "Got dns record: {:?} (type={})",
+                    res,
+                    self.dns.rtype_to_name(res.r#type)
+                );
+                match res.r#type {
+                    1 | 28 => {
+                        ttl = ttl.min(res.TTL as u64);
+                        res.data.parse().ok()
+                    }
+                    _ => None,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        if addrs.is_empty() {
+            bail!("No addresses found.")
+        }
+
+        let expires = Instant::now() + Duration::from_secs(ttl.max(MIN_TTL));
+        let mut cache = self.cache.lock().unwrap();
+        cache.insert(name.to_string(), (expires, addrs.clone()));
+
+        Ok(addrs)
+    }
+}
+
+pub async fn resolve(name: &str) -> Result<Vec<IpAddr>> {
+    Resolver::new()?.resolve(name).await
 }
 
 pub async fn run(args: Resolve) -> Result<()> {
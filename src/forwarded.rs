@@ -0,0 +1,249 @@
+use crate::cidr::Cidr;
+use crate::errors::*;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use warp::http::HeaderMap;
+
+fn is_trusted(ip: &IpAddr, trusted: &[Cidr]) -> bool {
+    trusted.iter().any(|cidr| cidr.contains(ip))
+}
+
+/// Parse a node like `1.2.3.4`, `1.2.3.4:1234`, `"[2001:db8::1]:1234"` or `2001:db8::1`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(rest) = node.strip_prefix('[') {
+        let end = rest.find(']')?;
+        return rest[..end].parse().ok();
+    }
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    // ipv4 with port, an ipv6 address without brackets was handled above
+    let (ip, _port) = node.split_at(node.rfind(':')?);
+    ip.parse().ok()
+}
+
+/// Collect all values of a header, multiple headers are treated like a single comma separated list
+fn list<'a>(headers: &'a HeaderMap, name: &str) -> Vec<&'a str> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect()
+}
+
+/// The `for=` parameters of the `Forwarded` header, see RFC 7239
+fn forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    list(headers, "forwarded")
+        .into_iter()
+        .map(|element| {
+            element.split(';').find_map(|pair| {
+                let mut kv = pair.splitn(2, '=');
+                let key = kv.next()?.trim();
+                let value = kv.next()?;
+                if key.eq_ignore_ascii_case("for") {
+                    // obfuscated identifiers like `_hidden` or `unknown` end up as None
+                    Some(parse_node(value))
+                } else {
+                    None
+                }
+            })
+        })
+        .map(|node| node.flatten())
+        .collect()
+}
+
+/// The header a trusted proxy uses to pass on the address of the client
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ForwardedHeader {
+    XForwardedFor,
+    Forwarded,
+    CfConnectingIp,
+}
+
+impl ForwardedHeader {
+    /// The addresses in the header, the proxy closest to us is last
+    fn chain(&self, headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+        match self {
+            ForwardedHeader::XForwardedFor => list(headers, "x-forwarded-for")
+                .into_iter()
+                .map(parse_node)
+                .collect(),
+            ForwardedHeader::Forwarded => forwarded_for(headers),
+            ForwardedHeader::CfConnectingIp => list(headers, "cf-connecting-ip")
+                .into_iter()
+                .map(parse_node)
+                .collect(),
+        }
+    }
+}
+
+impl FromStr for ForwardedHeader {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ForwardedHeader> {
+        match s {
+            "x-forwarded-for" => Ok(ForwardedHeader::XForwardedFor),
+            "forwarded" => Ok(ForwardedHeader::Forwarded),
+            "cf-connecting-ip" => Ok(ForwardedHeader::CfConnectingIp),
+            _ => bail!("Unknown forwarding header: {:?}", s),
+        }
+    }
+}
+
+impl fmt::Display for ForwardedHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ForwardedHeader::XForwardedFor => write!(f, "x-forwarded-for"),
+            ForwardedHeader::Forwarded => write!(f, "forwarded"),
+            ForwardedHeader::CfConnectingIp => write!(f, "cf-connecting-ip"),
+        }
+    }
+}
+
+/// Determine the address of the client, if the connection comes from a
+/// trusted proxy the configured forwarding header is consulted, all other
+/// headers are ignored. The chain of proxies is walked from the right and the
+/// first address that isn't trusted is used, so a client can't spoof its
+/// address by sending the header itself.
+///
+/// Connections over a unix socket don't have a peer address, they're only
+/// considered to come from a trusted proxy if `trust_unix` is set.
+pub fn client_addr(
+    peer: Option<IpAddr>,
+    headers: &HeaderMap,
+    header: ForwardedHeader,
+    trusted: &[Cidr],
+    trust_unix: bool,
+) -> Option<IpAddr> {
+    match peer {
+        Some(peer) if !is_trusted(&peer, trusted) => return Some(peer),
+        None if !trust_unix => return None,
+        _ => (),
+    }
+
+    let mut client = peer;
+    for node in header.chain(headers).into_iter().rev() {
+        match node {
+            Some(ip) => {
+                client = Some(ip);
+                if !is_trusted(&ip, trusted) {
+                    break;
+                }
+            }
+            None => {
+                warn!("Invalid address in {} header, using {:?}", header, client);
+                break;
+            }
+        }
+    }
+
+    if client != peer {
+        debug!(
+            "Using client address {:?} from {} header (peer {:?})",
+            client, header, peer
+        );
+    }
+    client
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header_map(list: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in list {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn test_untrusted_peer() {
+        let trusted = ["127.0.0.1".parse().unwrap()];
+        let headers = header_map(&[("x-forwarded-for", "1.2.3.4")]);
+        let client = client_addr(
+            ip("5.6.7.8"),
+            &headers,
+            ForwardedHeader::XForwardedFor,
+            &trusted,
+            false,
+        );
+        assert_eq!(client, ip("5.6.7.8"));
+    }
+
+    #[test]
+    fn test_spoofed_chain() {
+        let trusted = ["127.0.0.1".parse().unwrap()];
+        let headers = header_map(&[("x-forwarded-for", "1.1.1.1, 5.6.7.8")]);
+        let client = client_addr(
+            ip("127.0.0.1"),
+            &headers,
+            ForwardedHeader::XForwardedFor,
+            &trusted,
+            false,
+        );
+        assert_eq!(client, ip("5.6.7.8"));
+    }
+
+    #[test]
+    fn test_other_headers_ignored() {
+        let trusted = ["127.0.0.1".parse().unwrap()];
+        let headers = header_map(&[
+            ("cf-connecting-ip", "1.1.1.1"),
+            ("forwarded", "for=2.2.2.2"),
+            ("x-forwarded-for", "5.6.7.8"),
+        ]);
+        let client = client_addr(
+            ip("127.0.0.1"),
+            &headers,
+            ForwardedHeader::XForwardedFor,
+            &trusted,
+            false,
+        );
+        assert_eq!(client, ip("5.6.7.8"));
+
+        let headers = header_map(&[("x-forwarded-for", "5.6.7.8")]);
+        let client = client_addr(
+            ip("127.0.0.1"),
+            &headers,
+            ForwardedHeader::CfConnectingIp,
+            &trusted,
+            false,
+        );
+        assert_eq!(client, ip("127.0.0.1"));
+    }
+
+    #[test]
+    fn test_forwarded() {
+        let trusted = ["127.0.0.1".parse().unwrap()];
+        let headers = header_map(&[(
+            "forwarded",
+            "for=1.1.1.1, for=\"[2001:db8::1]:1234\";proto=https",
+        )]);
+        let client = client_addr(
+            ip("127.0.0.1"),
+            &headers,
+            ForwardedHeader::Forwarded,
+            &trusted,
+            false,
+        );
+        assert_eq!(client, ip("2001:db8::1"));
+    }
+
+    #[test]
+    fn test_unix_socket() {
+        let headers = header_map(&[("x-forwarded-for", "5.6.7.8")]);
+        let client = client_addr(None, &headers, ForwardedHeader::XForwardedFor, &[], false);
+        assert_eq!(client, None);
+        let client = client_addr(None, &headers, ForwardedHeader::XForwardedFor, &[], true);
+        assert_eq!(client, ip("5.6.7.8"));
+    }
+}
@@ -0,0 +1,386 @@
+use crate::args::Suggest;
+use crate::errors::*;
+use crate::quota;
+use crate::rules::{self, Pattern, Rule};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A destination the tunnel has seen
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Seen {
+    /// What the tunnel did with the last request, eg. `proxy` or `direct`
+    action: String,
+    /// The rule that matched the last request, None if nothing matched
+    rule: Option<String>,
+    count: u64,
+    /// Seconds since the unix epoch
+    first_seen: u64,
+    last_seen: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn date(secs: u64) -> String {
+    let (y, m, d) = quota::civil_from_days(secs / 86400);
+    format!("{:04}-{:02}-{:02}", y, m, d)
+}
+
+/// Records every destination requested from the tunnel, so new endpoints can
+/// be added to the rules. Nothing is recorded if no file is configured.
+pub struct Learner {
+    path: Option<PathBuf>,
+    seen: Mutex<HashMap<String, Seen>>,
+    dirty: AtomicBool,
+}
+
+impl Learner {
+    pub fn load(path: Option<PathBuf>) -> Result<Learner> {
+        let seen = match &path {
+            Some(path) if path.exists() => read(path)?,
+            _ => HashMap::new(),
+        };
+        Ok(Learner {
+            path,
+            seen: Mutex::new(seen),
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    /// Record a request and the decision of the tunnel, `rule` is None if
+    /// no rule matched
+    pub fn record(&self, addr: &str, action: &str, rule: Option<&Rule>) {
+        if self.path.is_none() {
+            return;
+        }
+
+        let now = now();
+        let mut seen = self.seen.lock().unwrap();
+        let seen = seen.entry(addr.to_string()).or_insert_with(|| {
+            info!("Learned new destination {:?} ({})", addr, action);
+            Seen {
+                action: String::new(),
+                rule: None,
+                count: 0,
+                first_seen: now,
+                last_seen: now,
+            }
+        });
+        seen.action = action.to_string();
+        seen.rule = rule.map(|r| r.to_string());
+        seen.count += 1;
+        seen.last_seen = now;
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let buf = {
+            let seen = self.seen.lock().unwrap();
+            // sorted so the file is easy to read and diff
+            let seen = seen.iter().collect::<BTreeMap<_, _>>();
+            serde_json::to_vec_pretty(&seen)?
+        };
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, &buf).with_context(|| anyhow!("Failed to write {:?}", tmp))?;
+        fs::rename(&tmp, path).with_context(|| anyhow!("Failed to replace {:?}", path))?;
+        debug!("Saved learned destinations to {:?}", path);
+        Ok(())
+    }
+}
+
+fn read(path: &PathBuf) -> Result<HashMap<String, Seen>> {
+    let buf = fs::read(path).with_context(|| anyhow!("Failed to read {:?}", path))?;
+    let seen =
+        serde_json::from_slice(&buf).with_context(|| anyhow!("Failed to parse {:?}", path))?;
+    Ok(seen)
+}
+
+/// Periodically write the learned destinations to disk
+pub async fn persist(learner: Arc<Learner>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(30));
+    loop {
+        interval.tick().await;
+        if let Err(err) = learner.save() {
+            error!("Failed to save learned destinations: {:#}", err);
+        }
+    }
+}
+
+fn in_domains(host: &str, domains: &[String]) -> bool {
+    domains.is_empty()
+        || domains.iter().any(|domain| {
+            let domain = domain.trim_start_matches("*.").trim_end_matches('.');
+            host == domain || host.ends_with(&format!(".{}", domain))
+        })
+}
+
+/// The domain a glob would cover, eg. `cdn3.signal.org` becomes `signal.org`.
+/// Ip addresses and names directly below a tld aren't merged.
+fn parent(host: &str) -> Option<&str> {
+    if host.parse::<IpAddr>().is_ok() || host.starts_with('[') {
+        return None;
+    }
+    let (_, parent) = host.split_at(host.find('.')? + 1);
+    if parent.contains('.') {
+        Some(parent)
+    } else {
+        None
+    }
+}
+
+struct Suggestion {
+    entry: String,
+    hosts: Vec<String>,
+    actions: Vec<String>,
+    count: u64,
+    first_seen: u64,
+    last_seen: u64,
+}
+
+impl Suggestion {
+    fn add(&mut self, addr: &str, seen: &Seen) {
+        self.hosts.push(addr.to_string());
+        if !self.actions.contains(&seen.action) {
+            self.actions.push(seen.action.clone());
+        }
+        self.count += seen.count;
+        self.first_seen = self.first_seen.min(seen.first_seen);
+        self.last_seen = self.last_seen.max(seen.last_seen);
+    }
+}
+
+/// Group the destinations that didn't match any rule into suggested entries,
+/// the most requested ones first
+fn suggestions(args: &Suggest, seen: &HashMap<String, Seen>) -> Result<Vec<Suggestion>> {
+    let mut suggestions = BTreeMap::<String, Suggestion>::new();
+    for (addr, seen) in seen {
+        if seen.rule.is_some() || seen.count < args.min_count {
+            continue;
+        }
+        let (host, port) = rules::split_addr(addr)?;
+        if !in_domains(host, &args.domains) {
+            continue;
+        }
+
+        let entry = match parent(host) {
+            Some(parent) if args.glob => format!("*.{}:{}", parent, port),
+            _ => addr.clone(),
+        };
+        suggestions
+            .entry(entry.clone())
+            .or_insert_with(|| Suggestion {
+                entry,
+                hosts: Vec::new(),
+                actions: Vec::new(),
+                count: 0,
+                first_seen: u64::MAX,
+                last_seen: 0,
+            })
+            .add(addr, seen);
+    }
+
+    let mut suggestions = suggestions
+        .into_values()
+        .map(|mut s| {
+            // a glob for a single host is less specific than needed
+            if s.hosts.len() == 1 {
+                s.entry = s.hosts[0].clone();
+            }
+            s.hosts.sort();
+            // make sure the suggestion is a valid pattern
+            s.entry.parse::<Pattern>()?;
+            Ok(s)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    suggestions.sort_by(|a, b| b.count.cmp(&a.count).then(a.entry.cmp(&b.entry)));
+    Ok(suggestions)
+}
+
+/// Print rules for destinations that didn't match any rule
+pub fn suggest(args: Suggest) -> Result<()> {
+    let seen = read(&args.path)?;
+    let suggestions = suggestions(&args, &seen)?;
+
+    if suggestions.is_empty() {
+        println!("# No unmatched destinations in {:?}", args.path);
+        return Ok(());
+    }
+
+    let flag = if args.backend { "-A" } else { "-F" };
+    for s in suggestions {
+        let mut comment = format!(
+            "{} requests, first seen {}, last seen {}, {}",
+            s.count,
+            date(s.first_seen),
+            date(s.last_seen),
+            s.actions.join("/")
+        );
+        if s.hosts.len() > 1 {
+            comment.push_str(&format!(" ({})", s.hosts.join(", ")));
+        }
+        println!("# {}", comment);
+        println!("{} '{}'", flag, s.entry);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    fn args(args: &[&str]) -> Suggest {
+        Suggest::from_iter(
+            ["suggest", "learned.json"]
+                .iter()
+                .chain(args.iter())
+                .copied(),
+        )
+    }
+
+    fn seen(entries: &[(&str, Option<&str>, u64)]) -> HashMap<String, Seen> {
+        entries
+            .iter()
+            .map(|(addr, rule, count)| {
+                let seen = Seen {
+                    action: if rule.is_some() { "proxy" } else { "direct" }.to_string(),
+                    rule: rule.map(String::from),
+                    count: *count,
+                    first_seen: 86400,
+                    last_seen: 86400 * 2,
+                };
+                (addr.to_string(), seen)
+            })
+            .collect()
+    }
+
+    fn entries(args: &Suggest, seen: &HashMap<String, Seen>) -> Vec<String> {
+        suggestions(args, seen)
+            .unwrap()
+            .into_iter()
+            .map(|s| s.entry)
+            .collect()
+    }
+
+    #[test]
+    fn test_in_domains() {
+        let domains = vec!["signal.org".to_string()];
+        assert!(in_domains("signal.org", &domains));
+        assert!(in_domains("cdn.signal.org", &domains));
+        assert!(!in_domains("notsignal.org", &domains));
+        assert!(!in_domains("signal.org.example.com", &domains));
+        assert!(in_domains("example.com", &[]));
+
+        let domains = vec!["*.signal.org.".to_string()];
+        assert!(in_domains("cdn.signal.org", &domains));
+    }
+
+    #[test]
+    fn test_parent() {
+        assert_eq!(parent("cdn3.signal.org"), Some("signal.org"));
+        assert_eq!(parent("a.b.example.com"), Some("b.example.com"));
+        assert_eq!(parent("signal.org"), None);
+        assert_eq!(parent("localhost"), None);
+        assert_eq!(parent("192.0.2.1"), None);
+        assert_eq!(parent("2001:db8::1"), None);
+        assert_eq!(parent("[2001:db8::1]"), None);
+    }
+
+    #[test]
+    fn test_suggest_unmatched_only() {
+        let seen = seen(&[
+            ("cdn.signal.org:443", Some("*.signal.org:443 -> proxy"), 5),
+            ("example.com:443", None, 2),
+            ("example.org:443", None, 7),
+        ]);
+        assert_eq!(
+            entries(&args(&[]), &seen),
+            &["example.org:443", "example.com:443"]
+        );
+        assert_eq!(
+            entries(&args(&["--min-count", "3"]), &seen),
+            &["example.org:443"]
+        );
+    }
+
+    #[test]
+    fn test_suggest_glob() {
+        let seen = seen(&[
+            ("cdn2.signal.org:443", None, 1),
+            ("cdn3.signal.org:443", None, 2),
+            ("cdn3.signal.org:80", None, 1),
+            ("updates.example.com:443", None, 1),
+        ]);
+        let s = suggestions(&args(&["--glob"]), &seen).unwrap();
+        assert_eq!(s[0].entry, "*.signal.org:443");
+        assert_eq!(s[0].hosts, &["cdn2.signal.org:443", "cdn3.signal.org:443"]);
+        assert_eq!(s[0].count, 3);
+        // a glob for a single host isn't suggested
+        assert_eq!(s[1].entry, "cdn3.signal.org:80");
+        assert_eq!(s[2].entry, "updates.example.com:443");
+        assert_eq!(s.len(), 3);
+
+        // without --glob every host is suggested on its own
+        assert_eq!(entries(&args(&[]), &seen).len(), 4);
+    }
+
+    #[test]
+    fn test_suggest_glob_ips_not_merged() {
+        let seen = seen(&[
+            ("192.0.2.1:443", None, 1),
+            ("192.0.2.2:443", None, 1),
+            ("[2001:db8::1]:443", None, 1),
+            ("[2001:db8::2]:443", None, 1),
+        ]);
+        assert_eq!(
+            entries(&args(&["--glob"]), &seen),
+            &[
+                "192.0.2.1:443",
+                "192.0.2.2:443",
+                "[2001:db8::1]:443",
+                "[2001:db8::2]:443"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_suggest_domain_filter() {
+        let seen = seen(&[
+            ("cdn2.signal.org:443", None, 1),
+            ("cdn3.signal.org:443", None, 1),
+            ("signal.org:443", None, 1),
+            ("example.com:443", None, 1),
+        ]);
+        assert_eq!(
+            entries(&args(&["--domain", "signal.org"]), &seen),
+            &[
+                "cdn2.signal.org:443",
+                "cdn3.signal.org:443",
+                "signal.org:443"
+            ]
+        );
+        assert_eq!(
+            entries(&args(&["--domain", "signal.org", "--glob"]), &seen),
+            &["*.signal.org:443", "signal.org:443"]
+        );
+    }
+}
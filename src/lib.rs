@@ -1,10 +1,27 @@
 #![recursion_limit = "1024"]
 pub mod args;
 pub mod backend;
+pub mod cidr;
 pub mod common;
+pub mod config;
 pub mod connect;
 pub mod dns;
 pub mod errors;
+pub mod forwarded;
+pub mod learn;
+pub mod limits;
+pub mod listener;
+pub mod noise;
+pub mod peercred;
+pub mod pool;
+pub mod probe;
+pub mod profiles;
+pub mod proxy_protocol;
+pub mod quota;
+pub mod reload;
 pub mod rules;
+pub mod signal_desktop;
 pub mod socks5;
+pub mod tls;
 pub mod tunnel;
+pub mod upstream;
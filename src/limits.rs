@@ -0,0 +1,352 @@
+use crate::common::RejectReason;
+use crate::errors::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// A bucket that holds up to `rate` tokens and is refilled by `rate` tokens per second
+    pub fn new(rate: u64) -> TokenBucket {
+        TokenBucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, rate: u64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.rate = rate as f64;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+    }
+
+    pub fn try_take(&mut self, rate: u64, n: u64) -> bool {
+        self.refill(rate);
+        if self.tokens >= n as f64 {
+            self.tokens -= n as f64;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Take tokens even if the bucket is empty, returns how long to wait until
+    /// the debt is paid off
+    pub fn take(&mut self, rate: u64, n: u64) -> Duration {
+        self.refill(rate);
+        self.tokens -= n as f64;
+        if self.tokens < 0.0 && self.rate > 0.0 {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+
+    /// If the bucket would be full after a refill, it's then identical to a new one
+    fn is_full(&self) -> bool {
+        let elapsed = self.last.elapsed().as_secs_f64();
+        self.tokens + elapsed * self.rate >= self.rate
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    /// Concurrent sessions per client
+    pub sessions_per_client: Option<usize>,
+    /// New connections per second per client
+    pub connect_rate: Option<u64>,
+    /// Concurrent outbound connections in total
+    pub connections: Option<usize>,
+    /// Bytes per second in total
+    pub bandwidth: Option<u64>,
+    /// Bytes per second per client
+    pub client_bandwidth: Option<u64>,
+    /// Bytes per second per destination
+    pub destination_bandwidth: Option<u64>,
+    /// Bytes per day per client
+    pub daily_quota: Option<u64>,
+    /// Bytes per month per client
+    pub monthly_quota: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum BucketKey {
+    Global,
+    Client(String),
+    Destination(String),
+}
+
+#[derive(Default)]
+struct Counters {
+    total: usize,
+    sessions: HashMap<String, usize>,
+    buckets: HashMap<String, TokenBucket>,
+    bandwidth: HashMap<BucketKey, TokenBucket>,
+    pruned: Option<Instant>,
+}
+
+impl Counters {
+    /// Drop the buckets of clients and destinations that haven't been seen for a while
+    fn prune(&mut self) {
+        if let Some(pruned) = self.pruned {
+            if pruned.elapsed() < PRUNE_INTERVAL {
+                return;
+            }
+        }
+        self.pruned = Some(Instant::now());
+        self.buckets.retain(|_, bucket| !bucket.is_full());
+        self.bandwidth.retain(|_, bucket| !bucket.is_full());
+    }
+}
+
+/// Tracks active sessions across all clients, this outlives config reloads
+#[derive(Default)]
+pub struct Limiter {
+    counters: Mutex<Counters>,
+}
+
+impl Limiter {
+    /// Count a new connection of the client, this is checked before any
+    /// expensive work is done for the connection
+    pub fn check_rate(
+        &self,
+        limits: &Limits,
+        client: &str,
+    ) -> std::result::Result<(), RejectReason> {
+        let mut counters = self.counters.lock().unwrap();
+        counters.prune();
+
+        if let Some(rate) = limits.connect_rate {
+            let bucket = counters
+                .buckets
+                .entry(client.to_string())
+                .or_insert_with(|| TokenBucket::new(rate));
+            if !bucket.try_take(rate, 1) {
+                warn!("Client {:?} exceeded the connection rate", client);
+                return Err(RejectReason::RateLimited);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn acquire(
+        self: &Arc<Self>,
+        limits: &Limits,
+        client: &str,
+    ) -> std::result::Result<Permit, RejectReason> {
+        let mut counters = self.counters.lock().unwrap();
+
+        if let Some(max) = limits.connections {
+            if counters.total >= max {
+                warn!("Reached the limit of {} concurrent connections", max);
+                return Err(RejectReason::TooManyConnections);
+            }
+        }
+
+        let sessions = counters.sessions.get(client).copied().unwrap_or(0);
+        if let Some(max) = limits.sessions_per_client {
+            if sessions >= max {
+                warn!(
+                    "Client {:?} reached the limit of {} concurrent sessions",
+                    client, max
+                );
+                return Err(RejectReason::TooManyConnections);
+            }
+        }
+
+        counters.total += 1;
+        counters.sessions.insert(client.to_string(), sessions + 1);
+        debug!(
+            "Client {:?} has {} active sessions, {} in total",
+            client,
+            sessions + 1,
+            counters.total
+        );
+
+        Ok(Permit {
+            limiter: Arc::clone(self),
+            client: client.to_string(),
+        })
+    }
+
+    /// Account for transferred bytes and wait if a bandwidth limit was exceeded
+    pub async fn throttle(&self, limits: &Limits, client: &str, dest: &str, n: usize) {
+        let delay = {
+            let mut counters = self.counters.lock().unwrap();
+            counters.prune();
+
+            let keys = [
+                (BucketKey::Global, limits.bandwidth),
+                (
+                    BucketKey::Client(client.to_string()),
+                    limits.client_bandwidth,
+                ),
+                (
+                    BucketKey::Destination(dest.to_string()),
+                    limits.destination_bandwidth,
+                ),
+            ];
+
+            let mut delay = Duration::from_secs(0);
+            for (key, rate) in keys.iter() {
+                if let Some(rate) = rate {
+                    let bucket = counters
+                        .bandwidth
+                        .entry(key.clone())
+                        .or_insert_with(|| TokenBucket::new(*rate));
+                    delay = delay.max(bucket.take(*rate, n as u64));
+                }
+            }
+            delay
+        };
+
+        if delay > Duration::from_secs(0) {
+            trace!("Throttling {:?} for {:?}", client, delay);
+            tokio::time::delay_for(delay).await;
+        }
+    }
+
+    fn release(&self, client: &str) {
+        let mut counters = self.counters.lock().unwrap();
+        counters.total -= 1;
+        if let Some(sessions) = counters.sessions.get_mut(client) {
+            *sessions -= 1;
+            if *sessions == 0 {
+                counters.sessions.remove(client);
+            }
+        }
+    }
+}
+
+/// An active session, released when dropped
+pub struct Permit {
+    limiter: Arc<Limiter>,
+    client: String,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.limiter.release(&self.client);
+    }
+}
+
+/// A bandwidth limit that is shared between connections
+#[derive(Clone)]
+pub struct Throttle {
+    rate: Option<u64>,
+    bucket: Arc<Mutex<TokenBucket>>,
+}
+
+impl Throttle {
+    pub fn new(rate: Option<u64>) -> Throttle {
+        Throttle {
+            rate,
+            bucket: Arc::new(Mutex::new(TokenBucket::new(rate.unwrap_or(0)))),
+        }
+    }
+
+    /// Use a different rate but keep sharing the bucket
+    pub fn with_rate(&self, rate: Option<u64>) -> Throttle {
+        Throttle {
+            rate,
+            bucket: Arc::clone(&self.bucket),
+        }
+    }
+
+    pub async fn consume(&self, n: usize) {
+        if let Some(rate) = self.rate {
+            let delay = {
+                let mut bucket = self.bucket.lock().unwrap();
+                bucket.take(rate, n as u64)
+            };
+            if delay > Duration::from_secs(0) {
+                trace!("Throttling for {:?}", delay);
+                tokio::time::delay_for(delay).await;
+            }
+        }
+    }
+}
+
+impl Default for Throttle {
+    fn default() -> Throttle {
+        Throttle::new(None)
+    }
+}
+
+/// Upload and download limits of the local tunnel
+#[derive(Clone, Default)]
+pub struct Throttles {
+    pub upload: Throttle,
+    pub download: Throttle,
+}
+
+/// Parse a number of bytes with an optional `K`, `M` or `G` suffix
+pub fn parse_bytes(s: &str) -> Result<u64> {
+    let (num, factor) = match s.chars().last() {
+        Some('K') | Some('k') => (&s[..s.len() - 1], 1024),
+        Some('M') | Some('m') => (&s[..s.len() - 1], 1024 * 1024),
+        Some('G') | Some('g') => (&s[..s.len() - 1], 1024 * 1024 * 1024),
+        _ => (s, 1),
+    };
+    let num = num
+        .parse::<u64>()
+        .with_context(|| anyhow!("Invalid number of bytes: {:?}", s))?;
+    num.checked_mul(factor)
+        .ok_or_else(|| anyhow!("Number of bytes is too large: {:?}", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_bucket_refills_while_unused() {
+        let mut bucket = TokenBucket::new(100_000);
+        assert!(bucket.try_take(100_000, 1_000));
+        assert!(!bucket.is_full());
+        thread::sleep(Duration::from_millis(50));
+        assert!(bucket.is_full());
+    }
+
+    #[test]
+    fn test_prune_stale_buckets() {
+        let limiter = Arc::new(Limiter::default());
+        let limits = Limits {
+            connect_rate: Some(100),
+            ..Default::default()
+        };
+        limiter.check_rate(&limits, "a").unwrap();
+        {
+            let mut counters = limiter.counters.lock().unwrap();
+            assert_eq!(counters.buckets.len(), 1);
+            counters.buckets.get_mut("a").unwrap().last -= Duration::from_secs(1);
+            counters.pruned = Some(Instant::now() - PRUNE_INTERVAL);
+        }
+        limiter.check_rate(&limits, "b").unwrap();
+        let counters = limiter.counters.lock().unwrap();
+        assert!(!counters.buckets.contains_key("a"));
+        assert!(counters.buckets.contains_key("b"));
+    }
+
+    #[test]
+    fn test_parse_bytes() {
+        assert_eq!(parse_bytes("1234").unwrap(), 1234);
+        assert_eq!(parse_bytes("2K").unwrap(), 2048);
+        assert_eq!(parse_bytes("1m").unwrap(), 1024 * 1024);
+        assert_eq!(parse_bytes("3G").unwrap(), 3 * 1024 * 1024 * 1024);
+        assert!(parse_bytes("99999999999G").is_err());
+        assert!(parse_bytes("G").is_err());
+        assert!(parse_bytes("-1").is_err());
+    }
+}
@@ -0,0 +1,173 @@
+use crate::errors::*;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::pin::Pin;
+use std::process;
+use std::task::{self, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+
+/// Parse file permissions in octal notation (eg. `660`)
+pub fn parse_mode(s: &str) -> Result<u32> {
+    let mode = u32::from_str_radix(s, 8).with_context(|| anyhow!("Invalid file mode: {:?}", s))?;
+    if mode > 0o7777 {
+        bail!("Invalid file mode: {:?}", s);
+    }
+    Ok(mode)
+}
+
+/// Bind the socket in a directory that's only accessible by us and move it into
+/// place after the permissions are set, so nobody can connect in between
+fn bind_with_mode(path: &Path, mode: u32) -> Result<UnixListener> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid socket path: {:?}", path))?;
+    let mut dir = OsString::from(".");
+    dir.push(name);
+    dir.push(format!(".{}.tmp", process::id()));
+    let dir = path.with_file_name(dir);
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .with_context(|| anyhow!("Failed to create directory: {:?}", dir))?;
+
+    let tmp = dir.join("sock");
+    let listener = UnixListener::bind(&tmp)
+        .with_context(|| anyhow!("Failed to bind unix socket: {:?}", tmp))
+        .and_then(|listener| {
+            fs::set_permissions(&tmp, fs::Permissions::from_mode(mode))
+                .with_context(|| anyhow!("Failed to set permissions of {:?}", tmp))?;
+            fs::rename(&tmp, path)
+                .with_context(|| anyhow!("Failed to move unix socket to {:?}", path))?;
+            Ok(listener)
+        });
+    if listener.is_err() {
+        fs::remove_file(&tmp).ok();
+    }
+    fs::remove_dir(&dir).with_context(|| anyhow!("Failed to remove directory: {:?}", dir))?;
+    listener
+}
+
+/// Listen on a tcp address or on a unix socket if the address starts with `unix:`
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    pub async fn bind(addr: &str, mode: Option<u32>) -> Result<Listener> {
+        if let Some(path) = addr.strip_prefix("unix:") {
+            let path = Path::new(path);
+            // remove a stale socket from a previous run, but nothing else
+            if let Ok(md) = fs::symlink_metadata(path) {
+                if !md.file_type().is_socket() {
+                    bail!("Refusing to replace {:?}, it's not a socket", path);
+                }
+                fs::remove_file(path)
+                    .with_context(|| anyhow!("Failed to remove stale socket: {:?}", path))?;
+            }
+
+            let listener = match mode {
+                Some(mode) => bind_with_mode(path, mode)?,
+                None => UnixListener::bind(path)
+                    .with_context(|| anyhow!("Failed to bind unix socket: {:?}", path))?,
+            };
+            Ok(Listener::Unix(listener))
+        } else {
+            if mode.is_some() {
+                warn!("The socket mode is only used for unix sockets");
+            }
+            let listener = TcpListener::bind(addr)
+                .await
+                .with_context(|| anyhow!("Failed to bind tcp socket: {:?}", addr))?;
+            Ok(Listener::Tcp(listener))
+        }
+    }
+
+    pub async fn accept(&mut self) -> Result<(Stream, PeerAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Stream::Tcp(stream), PeerAddr::Tcp(addr)))
+            }
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Stream::Unix(stream), PeerAddr::Unix))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    Unix,
+}
+
+impl PeerAddr {
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            PeerAddr::Tcp(addr) => Some(addr.ip()),
+            PeerAddr::Unix => None,
+        }
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PeerAddr::Tcp(addr) => write!(f, "{}", addr),
+            PeerAddr::Unix => write!(f, "unix socket"),
+        }
+    }
+}
+
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut *self {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut *self {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<io::Result<()>> {
+        match &mut *self {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<io::Result<()>> {
+        match &mut *self {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
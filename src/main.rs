@@ -1,15 +1,22 @@
 use env_logger::Env;
-use signal_doh_ech::args::{Args, SubCommand};
+use signal_doh_ech::args::{Args, Config, Rules, SubCommand};
 use signal_doh_ech::backend;
+use signal_doh_ech::config::{self, Loader};
 use signal_doh_ech::connect;
 use signal_doh_ech::dns;
 use signal_doh_ech::errors::*;
+use signal_doh_ech::learn;
+use signal_doh_ech::noise;
+use signal_doh_ech::probe;
+use signal_doh_ech::profiles;
+use signal_doh_ech::signal_desktop;
 use signal_doh_ech::tunnel;
 use structopt::StructOpt;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args = Args::from_args();
+    let matches = Args::clap().get_matches();
+    let args = Args::from_clap(&matches);
     let level = match args.verbose {
         0 => "off",
         1 => "info",
@@ -19,12 +26,20 @@ async fn main() -> Result<()> {
     };
     env_logger::init_from_env(Env::default().default_filter_or(level));
 
+    let loader = Loader::new(args, matches);
+    let args = loader.load()?;
+
     match args.subcommand {
         SubCommand::Connect(args) => connect::run(args).await?,
         SubCommand::Resolve(args) => dns::run(args).await?,
-        SubCommand::Tunnel(args) => tunnel::run(args).await?,
-        SubCommand::Backend(args) => backend::run(args).await?,
-        // SubCommand::Ping(_args) => (),
+        SubCommand::Tunnel(args) => tunnel::run(args, loader).await?,
+        SubCommand::Backend(args) => backend::run(args, loader).await?,
+        SubCommand::Profile(args) => profiles::run(args)?,
+        SubCommand::Config(Config::Check) => config::check(&args)?,
+        SubCommand::Rules(Rules::Suggest(args)) => learn::suggest(args)?,
+        SubCommand::Rules(Rules::FromSignal(args)) => signal_desktop::run(args)?,
+        SubCommand::Ping(args) => probe::run(args).await?,
+        SubCommand::Keygen(args) => noise::run(args)?,
         SubCommand::Completions(args) => args.gen_completions()?,
     }
 
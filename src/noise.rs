@@ -0,0 +1,297 @@
+use crate::args::Keygen;
+use crate::errors::*;
+use snow::{Builder, HandshakeState, TransportState};
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::str::FromStr;
+
+const PATTERN: &str = "Noise_IK_25519_ChaChaPoly_BLAKE2s";
+const MAX_MSG_LEN: usize = 65535;
+const TAG_LEN: usize = 16;
+
+fn builder() -> Builder<'static> {
+    Builder::new(PATTERN.parse().expect("Invalid noise pattern"))
+}
+
+/// A x25519 public key, encoded as base64
+#[derive(Clone, Copy, PartialEq)]
+pub struct PublicKey([u8; 32]);
+
+impl FromStr for PublicKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<PublicKey> {
+        let bytes = base64::decode(s.trim()).context("Public key is not valid base64")?;
+        let key = bytes
+            .as_slice()
+            .try_into()
+            .map_err(|_| anyhow!("Public key needs to be 32 bytes, got {}", bytes.len()))?;
+        Ok(PublicKey(key))
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", base64::encode(self.0))
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PublicKey({})", self)
+    }
+}
+
+/// A x25519 private key, stored as base64 in a file that's only readable by us
+pub struct PrivateKey([u8; 32]);
+
+impl PrivateKey {
+    pub fn generate() -> Result<PrivateKey> {
+        let keypair = builder().generate_keypair()?;
+        let key = keypair.private.as_slice().try_into()?;
+        Ok(PrivateKey(key))
+    }
+
+    pub fn load(path: &Path) -> Result<PrivateKey> {
+        let md = fs::metadata(path).with_context(|| anyhow!("Failed to read {:?}", path))?;
+        if md.permissions().mode() & 0o077 != 0 {
+            warn!("Private key {:?} is accessible by other users", path);
+        }
+        let text =
+            fs::read_to_string(path).with_context(|| anyhow!("Failed to read {:?}", path))?;
+        let bytes = base64::decode(text.trim())
+            .with_context(|| anyhow!("Private key in {:?} is not valid base64", path))?;
+        let key = bytes
+            .as_slice()
+            .try_into()
+            .map_err(|_| anyhow!("Private key in {:?} needs to be 32 bytes", path))?;
+        Ok(PrivateKey(key))
+    }
+
+    /// Write the key to a new file, existing files are never replaced
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+            .with_context(|| anyhow!("Failed to create {:?}", path))?;
+        writeln!(file, "{}", base64::encode(self.0))?;
+        Ok(())
+    }
+
+    pub fn public(&self) -> PublicKey {
+        let secret = x25519_dalek::StaticSecret::from(self.0);
+        PublicKey(x25519_dalek::PublicKey::from(&secret).to_bytes())
+    }
+}
+
+/// The encryption of the channel inside the websocket, plain if the proxy
+/// isn't configured with a key
+pub enum Session {
+    Plain,
+    Noise(Box<TransportState>),
+}
+
+impl Session {
+    pub fn encrypt(&mut self, msg: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Session::Plain => Ok(msg.to_vec()),
+            Session::Noise(noise) => {
+                if msg.len() + TAG_LEN > MAX_MSG_LEN {
+                    bail!("Message is too large to encrypt: {} bytes", msg.len());
+                }
+                let mut buf = vec![0u8; msg.len() + TAG_LEN];
+                let n = noise.write_message(msg, &mut buf)?;
+                buf.truncate(n);
+                Ok(buf)
+            }
+        }
+    }
+
+    pub fn decrypt(&mut self, msg: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Session::Plain => Ok(msg.to_vec()),
+            Session::Noise(noise) => {
+                let mut buf = vec![0u8; msg.len()];
+                let n = noise
+                    .read_message(msg, &mut buf)
+                    .map_err(|err| anyhow!("Failed to decrypt message: {}", err))?;
+                buf.truncate(n);
+                Ok(buf)
+            }
+        }
+    }
+}
+
+/// The client side of a handshake that's waiting for the response
+pub struct Initiator(HandshakeState);
+
+impl Initiator {
+    /// Start a handshake with the backend, without an identity a new key is
+    /// generated so connections can't be linked to each other
+    pub fn new(identity: Option<&PrivateKey>, backend: &PublicKey) -> Result<(Initiator, Vec<u8>)> {
+        let generated;
+        let identity = match identity {
+            Some(identity) => identity,
+            None => {
+                generated = PrivateKey::generate()?;
+                &generated
+            }
+        };
+        let mut noise = builder()
+            .local_private_key(&identity.0)
+            .remote_public_key(&backend.0)
+            .build_initiator()?;
+
+        let mut buf = vec![0u8; MAX_MSG_LEN];
+        let n = noise.write_message(&[], &mut buf)?;
+        buf.truncate(n);
+        Ok((Initiator(noise), buf))
+    }
+
+    pub fn finish(mut self, msg: &[u8]) -> Result<Session> {
+        let mut buf = vec![0u8; MAX_MSG_LEN];
+        self.0
+            .read_message(msg, &mut buf)
+            .map_err(|err| anyhow!("Invalid handshake response, wrong backend key? {}", err))?;
+        let noise = self.0.into_transport_mode()?;
+        Ok(Session::Noise(Box::new(noise)))
+    }
+}
+
+/// Answer the handshake of a client and return the key it authenticated with
+pub fn respond(key: &PrivateKey, msg: &[u8]) -> Result<(Session, PublicKey, Vec<u8>)> {
+    let mut noise = builder().local_private_key(&key.0).build_responder()?;
+
+    let mut buf = vec![0u8; MAX_MSG_LEN];
+    noise
+        .read_message(msg, &mut buf)
+        .map_err(|err| anyhow!("Invalid handshake, wrong key or plain client? {}", err))?;
+    let client = noise
+        .get_remote_static()
+        .ok_or_else(|| anyhow!("Client didn't send its static key"))?
+        .try_into()?;
+
+    let n = noise.write_message(&[], &mut buf)?;
+    buf.truncate(n);
+    let noise = noise.into_transport_mode()?;
+    Ok((Session::Noise(Box::new(noise)), PublicKey(client), buf))
+}
+
+pub fn run(args: Keygen) -> Result<()> {
+    let key = if args.path.exists() {
+        PrivateKey::load(&args.path)?
+    } else {
+        let key = PrivateKey::generate()?;
+        key.save(&args.path)?;
+        info!("Wrote new private key to {:?}", args.path);
+        key
+    };
+    println!("{}", key.public());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake(
+        identity: Option<&PrivateKey>,
+        backend: &PrivateKey,
+    ) -> (Session, Session, PublicKey) {
+        let (initiator, msg) = Initiator::new(identity, &backend.public()).unwrap();
+        let (server, client_key, reply) = respond(backend, &msg).unwrap();
+        let client = initiator.finish(&reply).unwrap();
+        (client, server, client_key)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let backend = PrivateKey::generate().unwrap();
+        let (mut client, mut server, _) = handshake(None, &backend);
+
+        let msg = client.encrypt(b"hello").unwrap();
+        assert_ne!(msg, b"hello");
+        assert_eq!(server.decrypt(&msg).unwrap(), b"hello");
+
+        let msg = server.encrypt(b"world").unwrap();
+        assert_eq!(client.decrypt(&msg).unwrap(), b"world");
+
+        let msg = client.encrypt(&[0u8; MAX_MSG_LEN - TAG_LEN]).unwrap();
+        assert_eq!(server.decrypt(&msg).unwrap().len(), MAX_MSG_LEN - TAG_LEN);
+        assert!(client.encrypt(&[0u8; MAX_MSG_LEN]).is_err());
+    }
+
+    #[test]
+    fn test_tampered_message() {
+        let backend = PrivateKey::generate().unwrap();
+        let (mut client, mut server, _) = handshake(None, &backend);
+        let mut msg = client.encrypt(b"hello").unwrap();
+        msg[0] ^= 1;
+        assert!(server.decrypt(&msg).is_err());
+    }
+
+    #[test]
+    fn test_identity() {
+        let backend = PrivateKey::generate().unwrap();
+        let identity = PrivateKey::generate().unwrap();
+        let (_, _, key) = handshake(Some(&identity), &backend);
+        assert_eq!(key, identity.public());
+
+        // without an identity every connection uses a new key
+        let (_, _, a) = handshake(None, &backend);
+        let (_, _, b) = handshake(None, &backend);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn test_wrong_backend_key() {
+        let backend = PrivateKey::generate().unwrap();
+        let other = PrivateKey::generate().unwrap();
+        let (_, msg) = Initiator::new(None, &other.public()).unwrap();
+        assert!(respond(&backend, &msg).is_err());
+    }
+
+    #[test]
+    fn test_plain_client() {
+        let backend = PrivateKey::generate().unwrap();
+        assert!(respond(&backend, br#"{"addr":"example.com:443"}"#).is_err());
+    }
+
+    #[test]
+    fn test_invalid_response() {
+        let backend = PrivateKey::generate().unwrap();
+        let (initiator, _) = Initiator::new(None, &backend.public()).unwrap();
+        assert!(initiator.finish(&[0u8; 48]).is_err());
+    }
+
+    #[test]
+    fn test_parse_public_key() {
+        let key = PrivateKey::generate().unwrap().public();
+        assert_eq!(key.to_string().parse::<PublicKey>().unwrap(), key);
+        assert!("AAAA".parse::<PublicKey>().is_err());
+        assert!("not base64!".parse::<PublicKey>().is_err());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = std::env::temp_dir().join(format!("sde-noise-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("key");
+
+        let key = PrivateKey::generate().unwrap();
+        key.save(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(PrivateKey::load(&path).unwrap().public(), key.public());
+        // existing keys are never replaced
+        assert!(PrivateKey::generate().unwrap().save(&path).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
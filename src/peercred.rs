@@ -0,0 +1,218 @@
+use crate::errors::*;
+use crate::listener::Stream;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+
+/// The user and group of the process on the other end of a local connection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Credentials {
+    pub uid: u32,
+    /// Not known if the owning process of a tcp socket isn't visible to us
+    pub gid: Option<u32>,
+}
+
+impl Credentials {
+    pub fn is_allowed(&self, uids: &[u32], gids: &[u32]) -> bool {
+        uids.contains(&self.uid) || self.gid.map(|gid| gids.contains(&gid)).unwrap_or(false)
+    }
+}
+
+/// Format an address the way it shows up in /proc/net/tcp, every 32 bit word
+/// is printed in host byte order
+fn proc_addr(addr: &SocketAddr) -> String {
+    let octets = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    let mut s = String::new();
+    for word in octets.chunks(4) {
+        let word = u32::from_ne_bytes([word[0], word[1], word[2], word[3]]);
+        s.push_str(&format!("{:08X}", word));
+    }
+    s.push_str(&format!(":{:04X}", addr.port()));
+    s
+}
+
+/// Unwrap ipv4 mapped addresses, the client socket is listed in /proc/net/tcp
+/// even if we accepted it on a dual-stack listener
+fn unmap(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), addr.port()),
+            None => addr,
+        },
+        IpAddr::V4(_) => addr,
+    }
+}
+
+/// Find the uid and inode of the client end of a loopback connection
+fn tcp_owner(local: SocketAddr, peer: SocketAddr) -> Result<(u32, u64)> {
+    let (local, peer) = (unmap(local), unmap(peer));
+    let path = if peer.is_ipv4() {
+        "/proc/net/tcp"
+    } else {
+        "/proc/net/tcp6"
+    };
+    let table = fs::read_to_string(path).with_context(|| anyhow!("Failed to read {:?}", path))?;
+
+    // from the clients point of view our address is the remote address
+    let (src, dst) = (proc_addr(&peer), proc_addr(&local));
+    match find_socket(&table, &src, &dst).with_context(|| anyhow!("Failed to parse {:?}", path))? {
+        Some(owner) => Ok(owner),
+        None => bail!("Connection from {} not found in {:?}", peer, path),
+    }
+}
+
+/// Find the uid and inode of the socket from `src` to `dst` in a /proc/net/tcp table
+fn find_socket(table: &str, src: &str, dst: &str) -> Result<Option<(u32, u64)>> {
+    for line in table.lines().skip(1) {
+        let cols = line.split_whitespace().collect::<Vec<_>>();
+        if cols.len() < 10 || cols[1] != src || cols[2] != dst {
+            continue;
+        }
+        let uid = cols[7]
+            .parse()
+            .with_context(|| anyhow!("Invalid uid: {:?}", cols[7]))?;
+        let inode = cols[9]
+            .parse()
+            .with_context(|| anyhow!("Invalid inode: {:?}", cols[9]))?;
+        return Ok(Some((uid, inode)));
+    }
+    Ok(None)
+}
+
+/// Find a process that holds the socket and return its effective gid
+fn socket_gid(inode: u64) -> Option<u32> {
+    let needle = format!("socket:[{}]", inode);
+    for proc in fs::read_dir("/proc").ok()?.flatten() {
+        let fds = match fs::read_dir(proc.path().join("fd")) {
+            Ok(fds) => fds,
+            // not a process or not ours to look at
+            Err(_) => continue,
+        };
+        let found = fds.flatten().any(|fd| {
+            fs::read_link(fd.path())
+                .map(|link| link.to_str() == Some(&needle))
+                .unwrap_or(false)
+        });
+        if found {
+            let status = fs::read_to_string(proc.path().join("status")).ok()?;
+            let gids = status.lines().find(|l| l.starts_with("Gid:"))?;
+            return gids.split_whitespace().nth(2)?.parse().ok();
+        }
+    }
+    None
+}
+
+/// Determine the credentials of the peer, finding the gid of a tcp client
+/// means scanning /proc so it's only done if `with_gid` is set
+pub fn lookup(stream: &Stream, with_gid: bool) -> Result<Credentials> {
+    match stream {
+        Stream::Unix(stream) => {
+            let cred = stream.peer_cred()?;
+            Ok(Credentials {
+                uid: cred.uid,
+                gid: Some(cred.gid),
+            })
+        }
+        Stream::Tcp(stream) => {
+            let (uid, inode) = tcp_owner(stream.local_addr()?, stream.peer_addr()?)?;
+            Ok(Credentials {
+                uid,
+                gid: if with_gid { socket_gid(inode) } else { None },
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::fs::MetadataExt;
+
+    const TABLE: &str = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 0100007F:0438 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 1001 1 0000000000000000 100 0 0 10 0
+   1: 0100007F:C350 0100007F:0438 01 00000000:00000000 00:00000000 00000000  1000        0 1002 1 0000000000000000 20 4 30 10 -1
+   2: 0100007F:0438 0100007F:C350 01 00000000:00000000 00:00000000 00000000     0        0 1003 1 0000000000000000 20 4 30 10 -1
+   3: 0100007F:C351 0100007F:0438 01 00000000:00000000 00:00000000 00000000  1001        0 1004 1 0000000000000000 20 4 30 10 -1
+";
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn test_proc_addr_v4() {
+        let addr = "127.0.0.1:1080".parse().unwrap();
+        assert_eq!(proc_addr(&addr), "0100007F:0438");
+        let addr = "192.168.1.20:50000".parse().unwrap();
+        assert_eq!(proc_addr(&addr), "1401A8C0:C350");
+    }
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn test_proc_addr_v6() {
+        let addr = "[::1]:80".parse().unwrap();
+        assert_eq!(proc_addr(&addr), "00000000000000000000000001000000:0050");
+        let addr = "[2001:db8::1]:443".parse().unwrap();
+        assert_eq!(proc_addr(&addr), "B80D0120000000000000000001000000:01BB");
+    }
+
+    #[test]
+    fn test_unmap() {
+        let addr = "[::ffff:127.0.0.1]:1080".parse().unwrap();
+        assert_eq!(unmap(addr), "127.0.0.1:1080".parse().unwrap());
+        let addr = "[::1]:1080".parse().unwrap();
+        assert_eq!(unmap(addr), addr);
+    }
+
+    #[test]
+    fn test_find_socket() {
+        assert_eq!(
+            find_socket(TABLE, "0100007F:C350", "0100007F:0438").unwrap(),
+            Some((1000, 1002))
+        );
+        assert_eq!(
+            find_socket(TABLE, "0100007F:C351", "0100007F:0438").unwrap(),
+            Some((1001, 1004))
+        );
+        // our own end of the connection isn't the client
+        assert_eq!(
+            find_socket(TABLE, "0100007F:0438", "0100007F:C352").unwrap(),
+            None
+        );
+        assert_eq!(
+            find_socket(TABLE, "0100007F:C352", "0100007F:0438").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_find_socket_skips_header() {
+        // the header is never matched, even if the columns would line up
+        assert_eq!(
+            find_socket(TABLE, "local_address", "rem_address").unwrap(),
+            None
+        );
+        assert_eq!(
+            find_socket("", "0100007F:C350", "0100007F:0438").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_find_socket_invalid() {
+        let table = "header\n   0: 0100007F:C350 0100007F:0438 01 0:0 0:0 0 nobody 0 1002\n";
+        assert!(find_socket(table, "0100007F:C350", "0100007F:0438").is_err());
+    }
+
+    #[test]
+    fn test_tcp_owner() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, peer) = listener.accept().unwrap();
+        assert_eq!(peer, client.local_addr().unwrap());
+
+        let (uid, inode) = tcp_owner(server.local_addr().unwrap(), peer).unwrap();
+        assert_eq!(uid, fs::metadata("/proc/self").unwrap().uid());
+        assert!(inode > 0);
+    }
+}
@@ -0,0 +1,296 @@
+use crate::args::{Endpoint, Proxy, Strategy};
+use crate::errors::*;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Default)]
+struct Stats {
+    /// Consecutive failures
+    failures: u32,
+    /// The circuit breaker is open, skip this proxy until then
+    open_until: Option<Instant>,
+    /// Moving average of the handshake latency
+    latency: Option<Duration>,
+    /// Moving average of the hello round trip, only measured by probes
+    hello: Option<Duration>,
+}
+
+impl Stats {
+    fn is_open(&self, now: Instant) -> bool {
+        self.open_until.map(|until| until > now).unwrap_or(false)
+    }
+
+    /// The expected time until a new connection is usable
+    fn score(&self) -> Option<Duration> {
+        Some(self.latency? + self.hello.unwrap_or_default())
+    }
+}
+
+fn average(avg: Option<Duration>, sample: Duration) -> Duration {
+    match avg {
+        Some(avg) => (avg * 3 + sample) / 4,
+        None => sample,
+    }
+}
+
+fn millis(d: Option<Duration>) -> String {
+    d.map(|d| format!("{}ms", d.as_millis()))
+        .unwrap_or_else(|| "-".to_string())
+}
+
+/// Passive health tracking of the proxies by name, this outlives config reloads
+#[derive(Default)]
+pub struct Health {
+    stats: Mutex<HashMap<String, Stats>>,
+    next: AtomicUsize,
+}
+
+impl Health {
+    /// The order in which the proxies should be tried for a new connection.
+    /// Proxies with an open circuit breaker are moved to the end, so they're
+    /// only used if everything else fails.
+    pub fn candidates<'a>(&self, args: &Proxy, endpoints: &'a [Endpoint]) -> Vec<&'a Endpoint> {
+        let now = Instant::now();
+        let stats = self.stats.lock().unwrap();
+        let is_open = |e: &Endpoint| stats.get(&e.name).map(|s| s.is_open(now)).unwrap_or(false);
+
+        let mut healthy = endpoints.iter().filter(|e| !is_open(e)).collect::<Vec<_>>();
+        let mut open = endpoints.iter().filter(|e| is_open(e)).collect::<Vec<_>>();
+
+        match args.strategy {
+            Strategy::Ordered => (),
+            Strategy::RoundRobin => {
+                if !healthy.is_empty() {
+                    let n = self.next.fetch_add(1, Ordering::Relaxed) % healthy.len();
+                    healthy.rotate_left(n);
+                }
+            }
+            Strategy::LowestLatency => {
+                // proxies that failed recently go last, proxies we don't know
+                // anything about yet are tried first so they get measured
+                healthy.sort_by_key(|e| match stats.get(&e.name) {
+                    Some(stats) => (stats.failures, stats.score()),
+                    None => (0, None),
+                });
+            }
+        }
+        open.sort_by_key(|e| stats.get(&e.name).and_then(|s| s.open_until));
+
+        healthy.extend(open);
+        healthy
+    }
+
+    pub fn success(&self, name: &str, latency: Duration) {
+        let mut stats = self.stats.lock().unwrap();
+        let stats = stats.entry(name.to_string()).or_default();
+        if stats.open_until.is_some() {
+            info!("Proxy {:?} is healthy again", name);
+        }
+        stats.failures = 0;
+        stats.open_until = None;
+        stats.latency = Some(average(stats.latency, latency));
+        debug!(
+            "Proxy {:?} handshake took {:?} (average {:?})",
+            name, latency, stats.latency
+        );
+    }
+
+    /// Record a successful probe
+    pub fn probed(&self, name: &str, handshake: Duration, hello: Duration) {
+        self.success(name, handshake);
+        let mut stats = self.stats.lock().unwrap();
+        let stats = stats.entry(name.to_string()).or_default();
+        stats.hello = Some(average(stats.hello, hello));
+    }
+
+    /// A short summary of the measurements for logs and `ping`
+    pub fn describe(&self, name: &str) -> String {
+        let stats = self.stats.lock().unwrap();
+        match stats.get(name) {
+            Some(stats) => {
+                let state = if stats.is_open(Instant::now()) {
+                    "unhealthy"
+                } else {
+                    "healthy"
+                };
+                format!(
+                    "{}, handshake {}, hello {}, {} failures",
+                    state,
+                    millis(stats.latency),
+                    millis(stats.hello),
+                    stats.failures
+                )
+            }
+            None => "no measurements".to_string(),
+        }
+    }
+
+    pub fn failure(&self, args: &Proxy, name: &str) {
+        let mut stats = self.stats.lock().unwrap();
+        let stats = stats.entry(name.to_string()).or_default();
+        stats.failures += 1;
+        if stats.failures >= args.failure_threshold {
+            warn!(
+                "Proxy {:?} failed {} times in a row, skipping it for {}s",
+                name, stats.failures, args.cooldown
+            );
+            stats.open_until = Some(Instant::now() + Duration::from_secs(args.cooldown));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    fn proxy(args: &[&str]) -> Proxy {
+        Proxy::from_iter(std::iter::once("proxy").chain(args.iter().copied()))
+    }
+
+    fn endpoints(proxy: &Proxy, names: &[&str]) -> Vec<Endpoint> {
+        names
+            .iter()
+            .map(|name| Endpoint {
+                name: name.to_string(),
+                proxy: proxy.clone(),
+            })
+            .collect()
+    }
+
+    fn names(candidates: Vec<&Endpoint>) -> Vec<&str> {
+        candidates.iter().map(|e| e.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_ordered() {
+        let args = proxy(&["--strategy", "ordered"]);
+        let endpoints = endpoints(&args, &["a", "b", "c"]);
+        let health = Health::default();
+        health.success("c", Duration::from_millis(1));
+        for _ in 0..3 {
+            assert_eq!(
+                names(health.candidates(&args, &endpoints)),
+                &["a", "b", "c"]
+            );
+        }
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let args = proxy(&["--strategy", "round-robin"]);
+        let endpoints = endpoints(&args, &["a", "b", "c"]);
+        let health = Health::default();
+        assert_eq!(
+            names(health.candidates(&args, &endpoints)),
+            &["a", "b", "c"]
+        );
+        assert_eq!(
+            names(health.candidates(&args, &endpoints)),
+            &["b", "c", "a"]
+        );
+        assert_eq!(
+            names(health.candidates(&args, &endpoints)),
+            &["c", "a", "b"]
+        );
+        assert_eq!(
+            names(health.candidates(&args, &endpoints)),
+            &["a", "b", "c"]
+        );
+    }
+
+    #[test]
+    fn test_lowest_latency() {
+        let args = proxy(&["--strategy", "lowest-latency"]);
+        let endpoints = endpoints(&args, &["slow", "fast", "new", "failed"]);
+        let health = Health::default();
+        health.success("slow", Duration::from_millis(300));
+        health.success("fast", Duration::from_millis(20));
+        health.success("failed", Duration::from_millis(1));
+        health.failure(&args, "failed");
+        // unmeasured proxies are tried first, recent failures last
+        assert_eq!(
+            names(health.candidates(&args, &endpoints)),
+            &["new", "fast", "slow", "failed"]
+        );
+    }
+
+    #[test]
+    fn test_lowest_latency_includes_hello() {
+        let args = proxy(&["--strategy", "lowest-latency"]);
+        let endpoints = endpoints(&args, &["a", "b"]);
+        let health = Health::default();
+        health.probed("a", Duration::from_millis(10), Duration::from_millis(500));
+        health.probed("b", Duration::from_millis(50), Duration::from_millis(50));
+        assert_eq!(names(health.candidates(&args, &endpoints)), &["b", "a"]);
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let args = proxy(&["--failure-threshold", "2"]);
+        let endpoints = endpoints(&args, &["a", "b", "c"]);
+        let health = Health::default();
+
+        health.failure(&args, "a");
+        // below the threshold the order is kept
+        assert_eq!(
+            names(health.candidates(&args, &endpoints)),
+            &["a", "b", "c"]
+        );
+
+        health.failure(&args, "a");
+        assert_eq!(
+            names(health.candidates(&args, &endpoints)),
+            &["b", "c", "a"]
+        );
+        assert!(health.describe("a").starts_with("unhealthy"));
+
+        // a success closes the circuit breaker again
+        health.success("a", Duration::from_millis(1));
+        assert_eq!(
+            names(health.candidates(&args, &endpoints)),
+            &["a", "b", "c"]
+        );
+        assert!(health.describe("a").starts_with("healthy"));
+    }
+
+    #[test]
+    fn test_circuit_breaker_round_robin() {
+        let args = proxy(&["--strategy", "round-robin", "--failure-threshold", "1"]);
+        let endpoints = endpoints(&args, &["a", "b", "c"]);
+        let health = Health::default();
+        health.failure(&args, "b");
+        // only the healthy proxies are rotated, the failed one stays last
+        assert_eq!(
+            names(health.candidates(&args, &endpoints)),
+            &["a", "c", "b"]
+        );
+        assert_eq!(
+            names(health.candidates(&args, &endpoints)),
+            &["c", "a", "b"]
+        );
+    }
+
+    #[test]
+    fn test_circuit_breaker_cooldown() {
+        let args = proxy(&["--failure-threshold", "1", "--cooldown", "0"]);
+        let endpoints = endpoints(&args, &["a", "b"]);
+        let health = Health::default();
+        health.failure(&args, "a");
+        // the cooldown is over, the proxy is tried in its regular position
+        assert_eq!(names(health.candidates(&args, &endpoints)), &["a", "b"]);
+    }
+
+    #[test]
+    fn test_all_open() {
+        let args = proxy(&["--failure-threshold", "1"]);
+        let endpoints = endpoints(&args, &["a", "b"]);
+        let health = Health::default();
+        health.failure(&args, "b");
+        health.failure(&args, "a");
+        // everything failed, the one that recovers first is tried first
+        assert_eq!(names(health.candidates(&args, &endpoints)), &["b", "a"]);
+    }
+}
@@ -0,0 +1,123 @@
+use crate::args::{Endpoint, Ping, Proxy};
+use crate::common::HelloResponse;
+use crate::connect;
+use crate::errors::*;
+use crate::pool::Health;
+use futures::future;
+use std::time::{Duration, Instant};
+use tokio::time;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub struct Measurement {
+    /// tcp, tls and websocket setup
+    pub handshake: Duration,
+    /// Round trip of the hello, this includes the connection from the proxy to `addr`
+    pub hello: Duration,
+    pub response: HelloResponse,
+}
+
+/// Connect to the proxy the same way a tunneled connection would and request `addr`
+pub async fn probe(proxy: &Proxy, addr: &str) -> Result<Measurement> {
+    let run = async {
+        let start = Instant::now();
+        let mut conn = connect::open(proxy).await?;
+        let handshake = start.elapsed();
+
+        let start = Instant::now();
+        let response = connect::hello(&mut conn, addr).await?;
+        let hello = start.elapsed();
+        conn.close().await;
+
+        Ok(Measurement {
+            handshake,
+            hello,
+            response,
+        })
+    };
+    time::timeout(PROBE_TIMEOUT, run)
+        .await
+        .map_err(|_| anyhow!("Probe timed out"))?
+}
+
+async fn probe_endpoint(args: &Proxy, health: &Health, endpoint: &Endpoint) -> Result<Measurement> {
+    match probe(&endpoint.proxy, &args.probe_addr).await {
+        Ok(m) => {
+            health.probed(&endpoint.name, m.handshake, m.hello);
+            Ok(m)
+        }
+        Err(err) => {
+            health.failure(args, &endpoint.name);
+            Err(err)
+        }
+    }
+}
+
+/// Probe every configured proxy once and log the results
+pub async fn probe_all(args: &Proxy, health: &Health) {
+    let endpoints = match args.endpoints() {
+        Ok(endpoints) => endpoints,
+        Err(err) => {
+            warn!("Failed to probe proxies: {:#}", err);
+            return;
+        }
+    };
+
+    let probes = endpoints
+        .iter()
+        .map(|endpoint| probe_endpoint(args, health, endpoint));
+    let results = future::join_all(probes).await;
+
+    for (endpoint, result) in endpoints.iter().zip(results) {
+        if let Err(err) = result {
+            warn!("Probe of proxy {:?} failed: {:#}", endpoint.name, err);
+        }
+        info!(
+            "Proxy {:?}: {}",
+            endpoint.name,
+            health.describe(&endpoint.name)
+        );
+    }
+}
+
+pub async fn run(args: Ping) -> Result<()> {
+    let endpoints = args.proxy.endpoints()?;
+    let health = Health::default();
+    let mut ok = false;
+
+    for endpoint in &endpoints {
+        for _ in 0..args.count {
+            match probe_endpoint(&args.proxy, &health, endpoint).await {
+                Ok(m) => {
+                    ok = true;
+                    let response = match m.response {
+                        HelloResponse::Accepted => "accepted".to_string(),
+                        HelloResponse::Established(selection) => {
+                            format!("accepted, {}", selection)
+                        }
+                        HelloResponse::Rejected(reason) => format!("rejected: {}", reason),
+                    };
+                    println!(
+                        "{}: handshake={}ms hello={}ms ({})",
+                        endpoint.name,
+                        m.handshake.as_millis(),
+                        m.hello.as_millis(),
+                        response
+                    );
+                }
+                Err(err) => println!("{}: error: {:#}", endpoint.name, err),
+            }
+        }
+    }
+
+    println!();
+    for endpoint in health.candidates(&args.proxy, &endpoints) {
+        println!("{}: {}", endpoint.name, health.describe(&endpoint.name));
+    }
+
+    if !ok {
+        bail!("Every proxy failed");
+    }
+    Ok(())
+}
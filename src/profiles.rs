@@ -0,0 +1,86 @@
+use crate::args;
+use crate::errors::*;
+use crate::rules::Pattern;
+use std::fs;
+
+const BUNDLED: &[(&str, &str)] = &[("signal", include_str!("profiles/signal.txt"))];
+
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub name: String,
+    pub version: Option<String>,
+    pub patterns: Vec<Pattern>,
+}
+
+impl Profile {
+    pub fn parse(name: &str, text: &str) -> Result<Profile> {
+        let mut version = None;
+        let mut patterns = Vec::new();
+
+        for line in text.lines() {
+            let line = line.trim();
+            if let Some(comment) = line.strip_prefix('#') {
+                if let Some(v) = comment.trim().strip_prefix("version:") {
+                    version = Some(v.trim().to_string());
+                }
+            } else if !line.is_empty() {
+                let pattern = line
+                    .parse()
+                    .with_context(|| anyhow!("Invalid entry in profile {:?}", name))?;
+                patterns.push(pattern);
+            }
+        }
+
+        Ok(Profile {
+            name: name.to_string(),
+            version,
+            patterns,
+        })
+    }
+
+    /// Load a bundled profile by name, or a profile file if the name contains a `/`
+    pub fn load(name: &str) -> Result<Profile> {
+        let profile = if name.contains('/') {
+            let text = fs::read_to_string(name)
+                .with_context(|| anyhow!("Failed to read profile: {:?}", name))?;
+            Profile::parse(name, &text)?
+        } else if let Some((_, text)) = BUNDLED.iter().find(|(n, _)| *n == name) {
+            Profile::parse(name, text)?
+        } else {
+            bail!("Unknown profile: {:?}", name);
+        };
+
+        info!(
+            "Using profile {:?} (version {}, {} entries)",
+            profile.name,
+            profile.version.as_deref().unwrap_or("unknown"),
+            profile.patterns.len()
+        );
+        Ok(profile)
+    }
+}
+
+pub fn run(args: args::Profile) -> Result<()> {
+    match args.name {
+        Some(name) => {
+            let profile = Profile::load(&name)?;
+            if let Some(version) = profile.version {
+                println!("# version: {}", version);
+            }
+            for pattern in profile.patterns {
+                println!("{}", pattern);
+            }
+        }
+        None => {
+            for (name, text) in BUNDLED {
+                let profile = Profile::parse(name, text)?;
+                println!(
+                    "{} (version {})",
+                    name,
+                    profile.version.as_deref().unwrap_or("unknown")
+                );
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,254 @@
+use crate::errors::*;
+use nom::bytes::complete::take;
+use nom::number::complete::be_u16;
+use nom::IResult;
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\x00\r\nQUIT\n";
+/// The longest possible v1 header, including the trailing `\r\n`
+const V1_MAX_LEN: usize = 107;
+
+/// Read a HAProxy PROXY protocol v1 or v2 header and return the original
+/// source address, `None` if the sender didn't provide one (`LOCAL`/`UNKNOWN`)
+pub async fn read_header<R: AsyncRead + Unpin>(sock: &mut R) -> Result<Option<SocketAddr>> {
+    let mut buf = vec![0u8; V2_SIGNATURE.len()];
+    sock.read_exact(&mut buf).await?;
+
+    if buf == V2_SIGNATURE {
+        let mut hdr = [0u8; 4];
+        sock.read_exact(&mut hdr).await?;
+        let len = u16::from_be_bytes([hdr[2], hdr[3]]) as usize;
+        let mut payload = vec![0u8; len];
+        sock.read_exact(&mut payload).await?;
+        parse_v2(hdr[0], hdr[1], &payload)
+    } else if buf.starts_with(b"PROXY ") {
+        while !buf.ends_with(b"\r\n") {
+            if buf.len() >= V1_MAX_LEN {
+                bail!("PROXY protocol v1 header is too long");
+            }
+            let mut c = [0u8; 1];
+            sock.read_exact(&mut c).await?;
+            buf.push(c[0]);
+        }
+        parse_v1(&buf[..buf.len() - 2])
+    } else {
+        bail!("Connection didn't start with a PROXY protocol header");
+    }
+}
+
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>> {
+    let line = str::from_utf8(line).context("PROXY protocol v1 header is not utf8")?;
+    let parts = line.split(' ').collect::<Vec<_>>();
+
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", proto, src, _dst, sport, _dport] => {
+            let ip = src
+                .parse::<IpAddr>()
+                .with_context(|| anyhow!("Invalid source address: {:?}", src))?;
+            match (*proto, ip) {
+                ("TCP4", IpAddr::V4(_)) | ("TCP6", IpAddr::V6(_)) => (),
+                _ => bail!("Invalid PROXY protocol v1 header: {:?}", line),
+            }
+            let port = sport
+                .parse::<u16>()
+                .with_context(|| anyhow!("Invalid source port: {:?}", sport))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => bail!("Invalid PROXY protocol v1 header: {:?}", line),
+    }
+}
+
+fn parse_v2_inet(input: &[u8]) -> IResult<&[u8], SocketAddr> {
+    let (input, src) = take(4usize)(input)?;
+    let (input, _dst) = take(4usize)(input)?;
+    let (input, port) = be_u16(input)?;
+    let (input, _dport) = be_u16(input)?;
+    let src = <[u8; 4]>::try_from(src).unwrap();
+    Ok((input, SocketAddr::new(Ipv4Addr::from(src).into(), port)))
+}
+
+fn parse_v2_inet6(input: &[u8]) -> IResult<&[u8], SocketAddr> {
+    let (input, src) = take(16usize)(input)?;
+    let (input, _dst) = take(16usize)(input)?;
+    let (input, port) = be_u16(input)?;
+    let (input, _dport) = be_u16(input)?;
+    let src = <[u8; 16]>::try_from(src).unwrap();
+    Ok((input, SocketAddr::new(Ipv6Addr::from(src).into(), port)))
+}
+
+fn parse_v2(ver_cmd: u8, family: u8, payload: &[u8]) -> Result<Option<SocketAddr>> {
+    if ver_cmd >> 4 != 2 {
+        bail!("Unsupported PROXY protocol version: {}", ver_cmd >> 4);
+    }
+
+    match ver_cmd & 0x0f {
+        // LOCAL, eg. health checks of the proxy itself
+        0 => return Ok(None),
+        1 => (),
+        cmd => bail!("Unsupported PROXY protocol command: {}", cmd),
+    }
+
+    // the lower 4 bits are the transport protocol, we don't care if it's tcp or udp
+    let res = match family >> 4 {
+        1 => parse_v2_inet(payload),
+        2 => parse_v2_inet6(payload),
+        // AF_UNSPEC and AF_UNIX don't carry an ip address
+        _ => return Ok(None),
+    };
+
+    let (_tlvs, addr) =
+        res.map_err(|err| anyhow!("Invalid PROXY protocol v2 addresses: {:?}", err))?;
+    Ok(Some(addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2(ver_cmd: u8, family: u8, addrs: &[u8]) -> Vec<u8> {
+        let mut buf = V2_SIGNATURE.to_vec();
+        buf.push(ver_cmd);
+        buf.push(family);
+        buf.extend(&(addrs.len() as u16).to_be_bytes());
+        buf.extend(addrs);
+        buf
+    }
+
+    async fn read(mut buf: &[u8]) -> Result<Option<SocketAddr>> {
+        read_header(&mut buf).await
+    }
+
+    #[tokio::test]
+    async fn test_v1_tcp4() {
+        let mut buf = &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /"[..];
+        let addr = read_header(&mut buf).await.unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+        // nothing after the header is consumed
+        assert_eq!(buf, b"GET /");
+    }
+
+    #[tokio::test]
+    async fn test_v1_tcp6() {
+        let addr = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n")
+            .await
+            .unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:56324".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_v1_unknown() {
+        assert_eq!(read(b"PROXY UNKNOWN\r\n").await.unwrap(), None);
+        assert_eq!(
+            read(b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n")
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_v1_invalid() {
+        // address family doesn't match the protocol
+        assert!(read(b"PROXY TCP4 2001:db8::1 2001:db8::2 1 2\r\n")
+            .await
+            .is_err());
+        assert!(read(b"PROXY TCP4 192.0.2.1 198.51.100.1 65536 443\r\n")
+            .await
+            .is_err());
+        assert!(read(b"PROXY TCP4 192.0.2.1 198.51.100.1\r\n")
+            .await
+            .is_err());
+        assert!(read(b"PROXY TCP4 192.0.2.1 198.51.100.1 1 2")
+            .await
+            .is_err());
+        assert!(read(b"GET / HTTP/1.1\r\n\r\n").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_v1_too_long() {
+        let mut line = b"PROXY TCP6 ".to_vec();
+        line.extend(vec![b'f'; V1_MAX_LEN]);
+        line.extend(b"\r\n");
+        let err = read(&line).await.unwrap_err();
+        assert!(err.to_string().contains("too long"), "{:#}", err);
+
+        // the longest valid headers are still accepted
+        let line = b"PROXY TCP6 ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff 65535 65535\r\n";
+        assert!(read(line).await.unwrap().is_some());
+        let mut line = b"PROXY UNKNOWN ".to_vec();
+        line.extend(vec![b'f'; V1_MAX_LEN - line.len() - 2]);
+        line.extend(b"\r\n");
+        assert_eq!(line.len(), V1_MAX_LEN);
+        assert_eq!(read(&line).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_v2_local() {
+        let buf = v2(0x20, 0x00, &[]);
+        assert_eq!(read(&buf).await.unwrap(), None);
+        // the addresses of a LOCAL connection are ignored
+        let buf = v2(0x20, 0x11, &[127, 0, 0, 1, 127, 0, 0, 1, 0, 1, 0, 2]);
+        assert_eq!(read(&buf).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_v2_inet() {
+        let buf = v2(
+            0x21,
+            0x11,
+            &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb],
+        );
+        let addr = read(&buf).await.unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_v2_inet6() {
+        let src = "2001:db8::1".parse::<Ipv6Addr>().unwrap();
+        let dst = "2001:db8::2".parse::<Ipv6Addr>().unwrap();
+        let mut addrs = Vec::new();
+        addrs.extend(&src.octets());
+        addrs.extend(&dst.octets());
+        addrs.extend(&[0xdc, 0x04, 0x01, 0xbb]);
+        // tlvs after the addresses are ignored
+        addrs.extend(&[0x04, 0x00, 0x01, 0x00]);
+        let buf = v2(0x21, 0x21, &addrs);
+        let addr = read(&buf).await.unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:56324".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_v2_unspec() {
+        let buf = v2(0x21, 0x00, &[]);
+        assert_eq!(read(&buf).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_v2_truncated_addresses() {
+        let buf = v2(0x21, 0x11, &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc]);
+        assert!(read(&buf).await.is_err());
+        let buf = v2(0x21, 0x21, &[0; 35]);
+        assert!(read(&buf).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_v2_truncated_payload() {
+        // the length field promises more than the connection sends
+        let mut buf = v2(0x21, 0x11, &[192, 0, 2, 1, 198, 51, 100, 1, 0, 1, 0, 2]);
+        buf.truncate(buf.len() - 1);
+        assert!(read(&buf).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_v2_wrong_version() {
+        let addrs = [192, 0, 2, 1, 198, 51, 100, 1, 0, 1, 0, 2];
+        assert!(read(&v2(0x11, 0x11, &addrs)).await.is_err());
+        assert!(read(&v2(0x31, 0x11, &addrs)).await.is_err());
+        // unknown command
+        assert!(read(&v2(0x22, 0x11, &addrs)).await.is_err());
+    }
+}
@@ -0,0 +1,221 @@
+use crate::errors::*;
+use crate::limits::Limits;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct Usage {
+    /// Days since the unix epoch
+    day: u64,
+    day_bytes: u64,
+    /// Months since year 0
+    month: u64,
+    month_bytes: u64,
+    /// If we've already logged that the quota was exceeded in this period
+    #[serde(skip)]
+    day_warned: bool,
+    #[serde(skip)]
+    month_warned: bool,
+}
+
+impl Usage {
+    fn rotate(&mut self, day: u64, month: u64) {
+        if self.day != day {
+            self.day = day;
+            self.day_bytes = 0;
+            self.day_warned = false;
+        }
+        if self.month != month {
+            self.month = month;
+            self.month_bytes = 0;
+            self.month_warned = false;
+        }
+    }
+}
+
+/// Days since the unix epoch (UTC)
+fn days_since_epoch() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+        / 86400
+}
+
+/// Convert days since the unix epoch to year, month and day
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+pub fn civil_from_days(day: u64) -> (i64, i64, i64) {
+    let z = day as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m, d)
+}
+
+/// Returns the current day and month (both in UTC)
+fn today() -> (u64, u64) {
+    let day = days_since_epoch();
+    let (y, m, _) = civil_from_days(day);
+    (day, (y * 12 + m - 1) as u64)
+}
+
+/// Daily and monthly byte counters per client, optionally persisted to disk
+pub struct Quotas {
+    path: Option<PathBuf>,
+    usage: Mutex<HashMap<String, Usage>>,
+    dirty: AtomicBool,
+}
+
+impl Quotas {
+    pub fn load(path: Option<PathBuf>) -> Result<Quotas> {
+        let usage = match &path {
+            Some(path) if path.exists() => {
+                let buf = fs::read(path)
+                    .with_context(|| anyhow!("Failed to read quota file: {:?}", path))?;
+                serde_json::from_slice(&buf)
+                    .with_context(|| anyhow!("Failed to parse quota file: {:?}", path))?
+            }
+            _ => HashMap::new(),
+        };
+        Ok(Quotas {
+            path,
+            usage: Mutex::new(usage),
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    pub fn exceeded(&self, limits: &Limits, client: &str) -> bool {
+        if limits.daily_quota.is_none() && limits.monthly_quota.is_none() {
+            return false;
+        }
+
+        let (day, month) = today();
+        let mut usage = self.usage.lock().unwrap();
+        let usage = match usage.get_mut(client) {
+            Some(usage) => usage,
+            None => return false,
+        };
+        usage.rotate(day, month);
+
+        if let Some(quota) = limits.daily_quota {
+            if usage.day_bytes >= quota {
+                if !usage.day_warned {
+                    warn!("Client {:?} exceeded its daily quota", client);
+                    usage.day_warned = true;
+                }
+                return true;
+            }
+        }
+        if let Some(quota) = limits.monthly_quota {
+            if usage.month_bytes >= quota {
+                if !usage.month_warned {
+                    warn!("Client {:?} exceeded its monthly quota", client);
+                    usage.month_warned = true;
+                }
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn add(&self, client: &str, n: usize) {
+        let (day, month) = today();
+        let mut usage = self.usage.lock().unwrap();
+        let usage = usage.entry(client.to_string()).or_default();
+        usage.rotate(day, month);
+        usage.day_bytes += n as u64;
+        usage.month_bytes += n as u64;
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Forget clients that didn't transfer anything this month, so we don't
+    /// keep their addresses around longer than needed
+    fn prune(&self) {
+        let (day, month) = today();
+        let mut usage = self.usage.lock().unwrap();
+        let len = usage.len();
+        usage.retain(|_, usage| usage.month == month);
+        for usage in usage.values_mut() {
+            usage.rotate(day, month);
+        }
+        if usage.len() != len {
+            debug!("Removed the quota usage of {} clients", len - usage.len());
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        self.prune();
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let buf = {
+            let usage = self.usage.lock().unwrap();
+            serde_json::to_vec(&*usage)?
+        };
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, &buf).with_context(|| anyhow!("Failed to write {:?}", tmp))?;
+        fs::rename(&tmp, path).with_context(|| anyhow!("Failed to replace {:?}", path))?;
+        debug!("Saved quota usage to {:?}", path);
+        Ok(())
+    }
+}
+
+/// Periodically write the usage to disk
+pub async fn persist(quotas: Arc<Quotas>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(30));
+    loop {
+        interval.tick().await;
+        if let Err(err) = quotas.save() {
+            error!("Failed to save quota usage: {:#}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exceeded() {
+        let quotas = Quotas::load(None).unwrap();
+        let limits = Limits {
+            daily_quota: Some(1000),
+            ..Default::default()
+        };
+        assert!(!quotas.exceeded(&limits, "a"));
+        quotas.add("a", 999);
+        assert!(!quotas.exceeded(&limits, "a"));
+        quotas.add("a", 1);
+        assert!(quotas.exceeded(&limits, "a"));
+        assert!(quotas.exceeded(&limits, "a"));
+        assert!(!quotas.exceeded(&limits, "b"));
+    }
+
+    #[test]
+    fn test_prune_past_months() {
+        let quotas = Quotas::load(None).unwrap();
+        quotas.add("a", 100);
+        quotas.add("b", 100);
+        quotas.usage.lock().unwrap().get_mut("a").unwrap().month -= 1;
+        quotas.prune();
+        let usage = quotas.usage.lock().unwrap();
+        assert!(!usage.contains_key("a"));
+        assert!(usage.contains_key("b"));
+    }
+}
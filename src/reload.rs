@@ -0,0 +1,62 @@
+use crate::errors::*;
+use futures::{select, FutureExt, StreamExt};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Settings that can be swapped at runtime, connections keep using the
+/// snapshot they've started with
+pub struct Shared<T> {
+    inner: RwLock<Arc<T>>,
+}
+
+impl<T> Shared<T> {
+    pub fn new(value: T) -> Shared<T> {
+        Shared {
+            inner: RwLock::new(Arc::new(value)),
+        }
+    }
+
+    pub fn load(&self) -> Arc<T> {
+        let inner = self.inner.read().unwrap();
+        Arc::clone(&inner)
+    }
+
+    pub fn store(&self, value: T) {
+        let mut inner = self.inner.write().unwrap();
+        *inner = Arc::new(value);
+    }
+}
+
+fn mtime(path: &Option<PathBuf>) -> Option<SystemTime> {
+    let path = path.as_ref()?;
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Call `reload` on SIGHUP or if the config file has been modified
+pub async fn watch<F: Fn() -> Result<()>>(path: Option<PathBuf>, reload: F) -> Result<()> {
+    let mut hup = signal(SignalKind::hangup()).context("Failed to setup signal handler")?;
+    let mut interval = tokio::time::interval(Duration::from_secs(2));
+    let mut last_mtime = mtime(&path);
+
+    loop {
+        select! {
+            _ = hup.next().fuse() => info!("Received SIGHUP, reloading"),
+            _ = interval.tick().fuse() => {
+                let mtime = mtime(&path);
+                if mtime == last_mtime {
+                    continue;
+                }
+                last_mtime = mtime;
+                info!("Config file has changed, reloading");
+            },
+        }
+
+        match reload() {
+            Ok(_) => info!("Reloaded configuration"),
+            Err(err) => error!("Failed to reload configuration: {:#}", err),
+        }
+    }
+}
@@ -1,14 +1,606 @@
+use crate::cidr::Cidr;
 use crate::errors::*;
+use crate::upstream::Upstream;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
 
-pub fn matches(req: &str, rules: &[String]) -> bool {
+#[derive(Debug, Clone, PartialEq)]
+enum HostPattern {
+    Any,
+    Glob(String),
+    Cidr(Cidr),
+}
+
+impl HostPattern {
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Any => true,
+            HostPattern::Glob(pattern) => glob(pattern.as_bytes(), host.as_bytes()),
+            HostPattern::Cidr(cidr) => host
+                .parse::<IpAddr>()
+                .map(|ip| cidr.contains(&ip))
+                .unwrap_or(false),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum PortPattern {
+    Any,
+    Range(u16, u16),
+}
+
+impl PortPattern {
+    fn matches(&self, port: u16) -> bool {
+        match self {
+            PortPattern::Any => true,
+            PortPattern::Range(start, end) => (*start..=*end).contains(&port),
+        }
+    }
+}
+
+impl FromStr for PortPattern {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<PortPattern> {
+        if s == "*" {
+            return Ok(PortPattern::Any);
+        }
+
+        let (start, end) = if let Some(idx) = s.find('-') {
+            (&s[..idx], &s[idx + 1..])
+        } else {
+            (s, s)
+        };
+        let start = start
+            .parse()
+            .with_context(|| anyhow!("Invalid port: {:?}", s))?;
+        let end = end
+            .parse()
+            .with_context(|| anyhow!("Invalid port: {:?}", s))?;
+        if start > end {
+            bail!("Invalid port range: {:?}", s);
+        }
+        Ok(PortPattern::Range(start, end))
+    }
+}
+
+/// A destination pattern, either `*` or `host:port`
+///
+/// The host can be an exact name, a glob like `*.signal.org`, an ip address or
+/// a cidr range like `13.248.0.0/16`. The port can be a single port, a range
+/// like `8000-8999` or `*`. IPv6 addresses need to be in brackets.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pattern {
+    host: HostPattern,
+    port: PortPattern,
+    raw: String,
+}
+
+impl Pattern {
+    pub fn matches(&self, host: &str, port: u16) -> bool {
+        self.port.matches(port) && self.host.matches(host)
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Pattern> {
+        if s == "*" {
+            return Ok(Pattern {
+                host: HostPattern::Any,
+                port: PortPattern::Any,
+                raw: s.to_string(),
+            });
+        }
+
+        let (host, port) =
+            split_addr_raw(s).with_context(|| anyhow!("Invalid pattern: {:?}", s))?;
+        let port = port.parse()?;
+
+        let host = if host == "*" {
+            HostPattern::Any
+        } else if host.contains('/') || host.parse::<IpAddr>().is_ok() {
+            HostPattern::Cidr(host.parse()?)
+        } else {
+            HostPattern::Glob(canonicalize_glob(host)?)
+        };
+
+        Ok(Pattern {
+            host,
+            port,
+            raw: s.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.raw)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// Forward the connection through the proxy
+    Proxy,
+    /// Connect to the destination directly
+    Direct,
+    /// Accept the connection on the backend
+    Allow,
+    /// Refuse the connection
+    Block,
+    /// Forward the connection through a specific proxy, by name
+    Via(String),
+    /// Connect to the destination through a socks5 or http proxy instead of directly
+    Upstream(Upstream),
+}
+
+fn is_proxy_name(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+impl FromStr for Action {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Action> {
+        match s {
+            "proxy" => Ok(Action::Proxy),
+            "direct" => Ok(Action::Direct),
+            "allow" => Ok(Action::Allow),
+            "block" | "deny" => Ok(Action::Block),
+            _ if s.starts_with("proxy:") => {
+                let name = &s[6..];
+                if !is_proxy_name(name) {
+                    bail!("Invalid proxy name: {:?}", name);
+                }
+                Ok(Action::Via(name.to_string()))
+            }
+            _ if s.contains("://") => Ok(Action::Upstream(s.parse()?)),
+            _ => bail!(
+                "Unknown action {:?}, expected proxy, proxy:<name>, direct, allow, block or an upstream url",
+                s
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::Proxy => write!(f, "proxy"),
+            Action::Direct => write!(f, "direct"),
+            Action::Allow => write!(f, "allow"),
+            Action::Block => write!(f, "block"),
+            Action::Via(name) => write!(f, "proxy:{}", name),
+            Action::Upstream(upstream) => write!(f, "{}", upstream),
+        }
+    }
+}
+
+/// A pattern with an action, written as `pattern -> action`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub pattern: Pattern,
+    pub action: Action,
+}
+
+impl Rule {
+    pub fn new(pattern: Pattern, action: Action) -> Rule {
+        Rule { pattern, action }
+    }
+
+    /// Like the Display implementation but keeps the password of an upstream,
+    /// only use this to write the config
+    pub fn to_raw(&self) -> String {
+        match &self.action {
+            Action::Upstream(upstream) => format!("{} -> {}", self.pattern, upstream.as_raw()),
+            _ => self.to_string(),
+        }
+    }
+}
+
+impl FromStr for Rule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Rule> {
+        let idx = s
+            .find("->")
+            .ok_or_else(|| anyhow!("Rule is missing an action (pattern -> action): {:?}", s))?;
+        let pattern = s[..idx].trim().parse()?;
+        let action = s[idx + 2..].trim().parse()?;
+        Ok(Rule { pattern, action })
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} -> {}", self.pattern, self.action)
+    }
+}
+
+/// Make sure all rules use actions that are valid in this context
+pub fn validate(rules: &[Rule], valid: &[Action]) -> Result<()> {
     for rule in rules {
-        if rule == "*" {
-            debug!("Request to {:?} matched wildcard", req);
-            return true;
-        } else if rule == req {
-            debug!("Request to {:?} matched rule", req);
-            return true;
+        let supported = match &rule.action {
+            Action::Via(_) => valid.contains(&Action::Proxy),
+            Action::Upstream(_) => valid.contains(&Action::Direct),
+            action => valid.contains(action),
+        };
+        if !supported {
+            bail!(
+                "Action {:?} is not supported here: {}",
+                rule.action.to_string(),
+                rule
+            );
         }
     }
-    false
+    Ok(())
+}
+
+/// Split `host:port` or `[ipv6]:port` into its parts
+pub fn split_addr(addr: &str) -> Result<(&str, u16)> {
+    let (host, port) = split_addr_raw(addr)?;
+    let port = port
+        .parse()
+        .with_context(|| anyhow!("Invalid port: {:?}", addr))?;
+    Ok((host, port))
+}
+
+/// Like `split_addr` but the port is kept as string
+fn split_addr_raw(addr: &str) -> Result<(&str, &str)> {
+    let idx = addr
+        .rfind(':')
+        .ok_or_else(|| anyhow!("Missing port: {:?}", addr))?;
+    let (host, port) = (&addr[..idx], &addr[idx + 1..]);
+    let host = if host.starts_with('[') && host.ends_with(']') {
+        &host[1..host.len() - 1]
+    } else if host.contains(':') {
+        bail!("IPv6 addresses need to be in brackets: {:?}", addr);
+    } else {
+        host
+    };
+    Ok((host, port))
+}
+
+/// Normalize a hostname so equivalent spellings compare equal
+///
+/// Names are lowercased, a trailing dot is removed and international names are
+/// converted to punycode. IP addresses are brought into their canonical form,
+/// IPv4-mapped IPv6 addresses (eg. `::ffff:127.0.0.1`) become IPv4 addresses.
+/// Names that look like an alternative notation of an IPv4 address (eg. `0x7f.1`)
+/// are rejected since resolvers disagree on how to interpret them.
+pub fn canonicalize_host(host: &str) -> Result<String> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+        return Ok(ip.to_string());
+    }
+
+    let name = host.strip_suffix('.').unwrap_or(host);
+    if name.is_empty() {
+        bail!("Hostname is empty: {:?}", host);
+    }
+    let name = idna::domain_to_ascii(name)
+        .map_err(|err| anyhow!("Invalid hostname {:?}: {:?}", host, err))?;
+    if name.is_empty() || name.split('.').any(|label| label.is_empty()) {
+        bail!("Invalid hostname: {:?}", host);
+    }
+
+    let last = name.rsplit('.').next().unwrap_or("");
+    if last.bytes().all(|b| b.is_ascii_digit()) || last.starts_with("0x") {
+        bail!("Ambiguous ip address notation: {:?}", host);
+    }
+
+    Ok(name)
+}
+
+/// Normalize an address in the `host:port` format, see `canonicalize_host`
+pub fn canonicalize(addr: &str) -> Result<String> {
+    let (host, port) = split_addr(addr)?;
+    let host = canonicalize_host(host)?;
+    if host.contains(':') {
+        Ok(format!("[{}]:{}", host, port))
+    } else {
+        Ok(format!("{}:{}", host, port))
+    }
+}
+
+/// Like `canonicalize_host` but keeps wildcards intact
+fn canonicalize_glob(host: &str) -> Result<String> {
+    let name = host.strip_suffix('.').unwrap_or(host);
+    let labels = name
+        .split('.')
+        .map(|label| {
+            if label.contains('*') {
+                Ok(label.to_lowercase())
+            } else {
+                idna::domain_to_ascii(label).map_err(|err| anyhow!("{:?}", err))
+            }
+        })
+        .collect::<Result<Vec<_>>>()
+        .with_context(|| anyhow!("Invalid pattern: {:?}", host))?;
+    Ok(labels.join("."))
+}
+
+/// Match a pattern where `*` stands for any number of characters, on a
+/// mismatch only the most recent `*` is extended so this runs in O(n*m)
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // the position of the last `*` and the position in the text it was resumed at
+    let mut star = None;
+    while t < text.len() {
+        if pattern.get(p) == Some(&b'*') {
+            star = Some((p, t));
+            p += 1;
+        } else if pattern.get(p) == Some(&text[t]) {
+            p += 1;
+            t += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, t));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+/// Find the first rule that matches the request
+pub fn evaluate<'a>(req: &str, rules: &'a [Rule]) -> Option<&'a Rule> {
+    let (host, port) = match split_addr(req) {
+        Ok((host, port)) => (host, port),
+        Err(err) => {
+            warn!("Request to {:?} can't be matched: {:#}", req, err);
+            return None;
+        }
+    };
+
+    for (i, rule) in rules.iter().enumerate() {
+        if rule.pattern.matches(host, port) {
+            debug!("Request to {:?} matched rule #{}: {}", req, i + 1, rule);
+            return Some(rule);
+        }
+    }
+    debug!("Request to {:?} didn't match any rule", req);
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(s: &str) -> Pattern {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_glob() {
+        assert!(glob(b"*.signal.org", b"cdn.signal.org"));
+        assert!(glob(b"*.signal.org", b"a.b.signal.org"));
+        assert!(!glob(b"*.signal.org", b"signal.org"));
+        assert!(!glob(b"*.signal.org", b"cdn.signal.org.evil.com"));
+        assert!(glob(b"cdn*.signal.org", b"cdn3.signal.org"));
+        assert!(glob(b"cdn*.signal.org", b"cdn.signal.org"));
+        assert!(glob(b"*", b""));
+        assert!(glob(b"**", b"abc"));
+        assert!(glob(b"a*b*c", b"aXbYbZc"));
+        assert!(!glob(b"a*b*c", b"aXbYbZ"));
+        assert!(!glob(b"", b"a"));
+        assert!(glob(b"", b""));
+    }
+
+    #[test]
+    fn test_glob_backtracking() {
+        let text = "a".repeat(1000);
+        assert!(!glob(b"*a*a*a*a*a*a*a*a*a*a*a*a*b", text.as_bytes()));
+        assert!(glob(b"*a*a*a*a*a*a*a*a*a*a*a*a*", text.as_bytes()));
+    }
+
+    #[test]
+    fn test_pattern_glob() {
+        let p = pattern("*.signal.org:443");
+        assert!(p.matches("chat.signal.org", 443));
+        assert!(!p.matches("chat.signal.org", 80));
+        assert!(!p.matches("signal.org", 443));
+    }
+
+    #[test]
+    fn test_pattern_any() {
+        assert!(pattern("*").matches("example.com", 1));
+        assert!(pattern("*:443").matches("example.com", 443));
+        assert!(!pattern("*:443").matches("example.com", 80));
+        assert!(pattern("example.com:*").matches("example.com", 80));
+    }
+
+    #[test]
+    fn test_port_pattern() {
+        assert_eq!("*".parse::<PortPattern>().unwrap(), PortPattern::Any);
+        assert_eq!(
+            "443".parse::<PortPattern>().unwrap(),
+            PortPattern::Range(443, 443)
+        );
+        let range = "8000-8999".parse::<PortPattern>().unwrap();
+        assert_eq!(range, PortPattern::Range(8000, 8999));
+        assert!(range.matches(8000));
+        assert!(range.matches(8999));
+        assert!(!range.matches(9000));
+        assert!("9000-8000".parse::<PortPattern>().is_err());
+        assert!("65536".parse::<PortPattern>().is_err());
+        assert!("abc".parse::<PortPattern>().is_err());
+        assert!("".parse::<PortPattern>().is_err());
+    }
+
+    #[test]
+    fn test_pattern_cidr() {
+        let p = pattern("13.248.0.0/16:443");
+        assert!(p.matches("13.248.212.111", 443));
+        assert!(!p.matches("13.249.0.1", 443));
+        assert!(!p.matches("example.com", 443));
+
+        let p = pattern("[2001:db8::/32]:443");
+        assert!(p.matches("2001:db8::1", 443));
+        assert!(!p.matches("2001:db9::1", 443));
+
+        let p = pattern("127.0.0.1:1-1024");
+        assert!(p.matches("127.0.0.1", 80));
+        assert!(!p.matches("127.0.0.2", 80));
+    }
+
+    #[test]
+    fn test_invalid_patterns() {
+        assert!("example.com".parse::<Pattern>().is_err());
+        assert!("2001:db8::1:443".parse::<Pattern>().is_err());
+        assert!("10.0.0.0/33:443".parse::<Pattern>().is_err());
+    }
+
+    #[test]
+    fn test_canonicalize_case() {
+        assert_eq!(
+            canonicalize_host("Chat.Signal.ORG").unwrap(),
+            "chat.signal.org"
+        );
+        assert_eq!(
+            canonicalize("CDN.signal.org:443").unwrap(),
+            "cdn.signal.org:443"
+        );
+    }
+
+    #[test]
+    fn test_canonicalize_trailing_dot() {
+        assert_eq!(canonicalize_host("signal.org.").unwrap(), "signal.org");
+        assert_eq!(canonicalize("signal.org.:443").unwrap(), "signal.org:443");
+        assert!(canonicalize_host(".").is_err());
+        assert!(canonicalize_host("").is_err());
+        assert!(canonicalize_host("signal..org").is_err());
+    }
+
+    #[test]
+    fn test_canonicalize_idn() {
+        assert_eq!(
+            canonicalize_host("bücher.example").unwrap(),
+            "xn--bcher-kva.example"
+        );
+        assert_eq!(
+            canonicalize_host("BÜCHER.example.").unwrap(),
+            "xn--bcher-kva.example"
+        );
+        assert_eq!(
+            canonicalize_host("xn--bcher-kva.example").unwrap(),
+            "xn--bcher-kva.example"
+        );
+    }
+
+    #[test]
+    fn test_canonicalize_ip() {
+        assert_eq!(canonicalize_host("127.0.0.1").unwrap(), "127.0.0.1");
+        assert_eq!(canonicalize_host("2001:DB8:0:0::1").unwrap(), "2001:db8::1");
+        assert_eq!(
+            canonicalize("[2001:0db8::0001]:443").unwrap(),
+            "[2001:db8::1]:443"
+        );
+        assert_eq!(canonicalize_host("::ffff:10.0.0.1").unwrap(), "10.0.0.1");
+        assert_eq!(canonicalize("[::ffff:a00:1]:443").unwrap(), "10.0.0.1:443");
+        assert_eq!(canonicalize_host("::1").unwrap(), "::1");
+    }
+
+    #[test]
+    fn test_canonicalize_ambiguous_ip() {
+        assert!(canonicalize_host("0x7f.1").is_err());
+        assert!(canonicalize_host("2130706433").is_err());
+        assert!(canonicalize_host("127.1").is_err());
+        assert!(canonicalize_host("1.2.3.08").is_err());
+    }
+
+    #[test]
+    fn test_canonicalize_glob() {
+        assert_eq!(canonicalize_glob("*.Signal.ORG.").unwrap(), "*.signal.org");
+        assert_eq!(
+            canonicalize_glob("*.bücher.example").unwrap(),
+            "*.xn--bcher-kva.example"
+        );
+        let p = pattern("*.BÜCHER.example.:443");
+        assert!(p.matches(&canonicalize_host("Www.Bücher.Example.").unwrap(), 443));
+    }
+
+    #[test]
+    fn test_mapped_ipv4_matches_rules() {
+        let rules = vec!["10.0.0.0/8:* -> block".parse::<Rule>().unwrap()];
+        let addr = canonicalize("[::ffff:10.1.2.3]:443").unwrap();
+        assert_eq!(
+            evaluate(&addr, &rules).map(|r| &r.action),
+            Some(&Action::Block)
+        );
+    }
+
+    #[test]
+    fn test_parse_action() {
+        assert_eq!("proxy".parse::<Action>().unwrap(), Action::Proxy);
+        assert_eq!("deny".parse::<Action>().unwrap(), Action::Block);
+        assert_eq!(
+            "proxy:main".parse::<Action>().unwrap(),
+            Action::Via("main".to_string())
+        );
+        assert_eq!(
+            "proxy:direct".parse::<Action>().unwrap(),
+            Action::Via("direct".to_string())
+        );
+        assert!("drect".parse::<Action>().is_err());
+        assert!("main".parse::<Action>().is_err());
+        assert!("proxy:".parse::<Action>().is_err());
+        assert!("proxy:a b".parse::<Action>().is_err());
+    }
+
+    #[test]
+    fn test_split_addr() {
+        assert_eq!(split_addr("example.com:443").unwrap(), ("example.com", 443));
+        assert_eq!(split_addr("[2001:db8::1]:80").unwrap(), ("2001:db8::1", 80));
+        assert!(split_addr("2001:db8::1:80").is_err());
+        assert!(split_addr("example.com").is_err());
+        assert!(split_addr("example.com:http").is_err());
+        assert!(split_addr("example.com:65536").is_err());
+    }
+
+    fn rules(rules: &[&str]) -> Vec<Rule> {
+        rules.iter().map(|r| r.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_evaluate_first_match_wins() {
+        let rules = rules(&[
+            "example.com:* -> block",
+            "*.example.com:443 -> direct",
+            "* -> proxy",
+        ]);
+        let action = |req| evaluate(req, &rules).map(|r| r.action.clone());
+        assert_eq!(action("example.com:443"), Some(Action::Block));
+        assert_eq!(action("www.example.com:443"), Some(Action::Direct));
+        assert_eq!(action("www.example.com:80"), Some(Action::Proxy));
+        assert_eq!(action("example.org:443"), Some(Action::Proxy));
+    }
+
+    #[test]
+    fn test_evaluate_returns_rule() {
+        let rules = rules(&["*.signal.org:443 -> proxy", "*.signal.org:* -> block"]);
+        let rule = evaluate("cdn.signal.org:80", &rules).unwrap();
+        assert_eq!(rule.to_raw(), "*.signal.org:* -> block");
+    }
+
+    #[test]
+    fn test_evaluate_no_match() {
+        let rules = rules(&["*.signal.org:443 -> proxy"]);
+        assert!(evaluate("example.com:443", &rules).is_none());
+        assert!(evaluate("signal.org:443", &rules).is_none());
+        assert!(evaluate("example.com:443", &[]).is_none());
+        // requests that can't be parsed never match
+        let any = self::rules(&["* -> proxy"]);
+        assert!(evaluate("example.com", &any).is_none());
+    }
 }
@@ -0,0 +1,342 @@
+use crate::args::FromSignal;
+use crate::errors::*;
+use crate::rules::Pattern;
+use serde_json::Value;
+use std::convert::TryInto;
+use std::fs;
+use std::path::{Path, PathBuf};
+use warp::http::Uri;
+
+/// signal-desktop uses node-config, the production config is applied on top of the defaults
+const DEFAULT_CONFIG: &str = "config/default.json";
+const CONFIG: &str = "config/production.json";
+
+/// Read a file from an asar archive, the format electron uses to bundle apps.
+/// The archive starts with a json index that's wrapped in a chromium pickle,
+/// the offsets of the files are relative to the end of the index.
+fn read_asar(path: &Path, name: &str) -> Result<Option<Vec<u8>>> {
+    let buf = fs::read(path).with_context(|| anyhow!("Failed to read {:?}", path))?;
+    let u32_at = |pos: usize| -> Result<usize> {
+        let bytes = buf
+            .get(pos..pos + 4)
+            .ok_or_else(|| anyhow!("Unexpected end of asar archive"))?;
+        Ok(u32::from_le_bytes(bytes.try_into()?) as usize)
+    };
+
+    if u32_at(0)? != 4 {
+        bail!("Not an asar archive: {:?}", path);
+    }
+    let header_size = u32_at(4)?;
+    let json_size = u32_at(12)?;
+    let json = buf
+        .get(16..16 + json_size)
+        .ok_or_else(|| anyhow!("Unexpected end of asar archive"))?;
+    let index = serde_json::from_slice::<Value>(json).context("Invalid asar index")?;
+
+    let mut entry = &index;
+    for part in name.split('/') {
+        entry = match entry.get("files").and_then(|files| files.get(part)) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+    }
+
+    if entry.get("unpacked").and_then(Value::as_bool) == Some(true) {
+        let mut unpacked = path.as_os_str().to_owned();
+        unpacked.push(".unpacked");
+        let unpacked = PathBuf::from(unpacked).join(name);
+        let buf = fs::read(&unpacked).with_context(|| anyhow!("Failed to read {:?}", unpacked))?;
+        return Ok(Some(buf));
+    }
+
+    let offset = entry
+        .get("offset")
+        .and_then(Value::as_str)
+        .and_then(|offset| offset.parse::<usize>().ok())
+        .ok_or_else(|| anyhow!("Invalid offset of {:?} in asar archive", name))?;
+    let size = entry
+        .get("size")
+        .and_then(Value::as_u64)
+        .ok_or_else(|| anyhow!("Invalid size of {:?} in asar archive", name))?;
+    let data = (8 + header_size)
+        .checked_add(offset)
+        .and_then(|start| Some(start..start.checked_add(size as usize)?))
+        .and_then(|range| buf.get(range))
+        .ok_or_else(|| anyhow!("Unexpected end of asar archive"))?;
+    Ok(Some(data.to_vec()))
+}
+
+fn read_file(path: &Path) -> Result<Option<Vec<u8>>> {
+    if path.exists() {
+        let buf = fs::read(path).with_context(|| anyhow!("Failed to read {:?}", path))?;
+        Ok(Some(buf))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Read a file of the app, from an asar archive, an extracted directory or
+/// the given production config, the defaults are expected next to it
+fn read_app_file(path: &Path, name: &str) -> Result<Option<Vec<u8>>> {
+    if path.is_dir() {
+        read_file(&path.join(name))
+    } else if path.extension().map(|ext| ext == "asar").unwrap_or(false) {
+        read_asar(path, name)
+    } else if name == CONFIG {
+        read_file(path)
+    } else if name == DEFAULT_CONFIG {
+        read_file(&path.with_file_name("default.json"))
+    } else {
+        Ok(None)
+    }
+}
+
+fn read_config(path: &Path, name: &str) -> Result<Option<Value>> {
+    match read_app_file(path, name)? {
+        Some(buf) => {
+            let config = serde_json::from_slice(&buf)
+                .with_context(|| anyhow!("Failed to parse {:?} in {:?}", name, path))?;
+            Ok(Some(config))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Apply a config on top of another one, objects are merged and everything
+/// else is replaced, like node-config does
+fn merge(base: &mut Value, config: Value) {
+    match (base, config) {
+        (Value::Object(base), Value::Object(config)) => {
+            for (key, value) in config {
+                match base.get_mut(&key) {
+                    Some(base) => merge(base, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, config) => *base = config,
+    }
+}
+
+/// Collect every url in the config, eg. `serverUrl`, the `cdn` map or `sfuUrl`
+fn collect_urls<'a>(value: &'a Value, urls: &mut Vec<&'a str>) {
+    match value {
+        Value::String(s)
+            if ["https://", "http://", "wss://", "ws://"]
+                .iter()
+                .any(|scheme| s.starts_with(scheme)) =>
+        {
+            urls.push(s);
+        }
+        Value::Array(values) => {
+            for value in values {
+                collect_urls(value, urls);
+            }
+        }
+        Value::Object(map) => {
+            for value in map.values() {
+                collect_urls(value, urls);
+            }
+        }
+        _ => (),
+    }
+}
+
+fn url_to_pattern(url: &str) -> Result<Pattern> {
+    let uri = url
+        .parse::<Uri>()
+        .with_context(|| anyhow!("Invalid url: {:?}", url))?;
+    let host = uri
+        .host()
+        .ok_or_else(|| anyhow!("Url has no host: {:?}", url))?;
+    let port = match (uri.port_u16(), uri.scheme_str()) {
+        (Some(port), _) => port,
+        (None, Some("http")) | (None, Some("ws")) => 80,
+        _ => 443,
+    };
+    format!("{}:{}", host, port).parse()
+}
+
+/// The servers signal-desktop connects to, and the version of the app if known
+pub fn endpoints(path: &Path) -> Result<(Vec<Pattern>, Option<String>)> {
+    let production = read_config(path, CONFIG)?
+        .ok_or_else(|| anyhow!("Could not find {:?} in {:?}", CONFIG, path))?;
+    let config = match read_config(path, DEFAULT_CONFIG)? {
+        Some(mut config) => {
+            merge(&mut config, production);
+            config
+        }
+        None => {
+            warn!(
+                "Could not find {:?} in {:?}, some endpoints may be missing",
+                DEFAULT_CONFIG, path
+            );
+            production
+        }
+    };
+
+    let mut urls = Vec::new();
+    collect_urls(&config, &mut urls);
+    let mut patterns = Vec::new();
+    for url in urls {
+        let pattern = match url_to_pattern(url) {
+            Ok(pattern) => pattern,
+            Err(err) => {
+                warn!("Skipping url: {:#}", err);
+                continue;
+            }
+        };
+        if !patterns.contains(&pattern) {
+            patterns.push(pattern);
+        }
+    }
+    patterns.sort_by_key(|p| p.to_string());
+
+    let version = read_app_file(path, "package.json")?
+        .and_then(|buf| serde_json::from_slice::<Value>(&buf).ok())
+        .and_then(|pkg| pkg.get("version")?.as_str().map(String::from));
+
+    Ok((patterns, version))
+}
+
+pub fn run(args: FromSignal) -> Result<()> {
+    let (patterns, version) = endpoints(&args.path)?;
+    info!(
+        "Found {} endpoints in {:?} (version {})",
+        patterns.len(),
+        args.path,
+        version.as_deref().unwrap_or("unknown")
+    );
+
+    if args.profile {
+        println!("# Endpoints used by signal-desktop");
+        if let Some(version) = version {
+            println!("# version: {}", version);
+        }
+        for pattern in patterns {
+            println!("{}", pattern);
+        }
+    } else {
+        let flag = if args.backend { "-A" } else { "-F" };
+        for pattern in patterns {
+            println!("{} '{}'", flag, pattern);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/signal-desktop")
+            .join(name)
+    }
+
+    #[test]
+    fn test_read_asar() {
+        let path = fixture("app.asar");
+        let pkg = read_asar(&path, "package.json").unwrap().unwrap();
+        let pkg = serde_json::from_slice::<Value>(&pkg).unwrap();
+        assert_eq!(pkg["version"], "7.0.0");
+
+        let config = read_asar(&path, CONFIG).unwrap().unwrap();
+        let config = serde_json::from_slice::<Value>(&config).unwrap();
+        assert_eq!(config["serverUrl"], "https://chat.signal.org");
+
+        assert_eq!(read_asar(&path, "config/local.json").unwrap(), None);
+        assert_eq!(read_asar(&path, "missing/production.json").unwrap(), None);
+    }
+
+    #[test]
+    fn test_read_asar_unpacked() {
+        let path = fixture("app.asar");
+        let buf = read_asar(&path, "node_modules/ringrtc.node")
+            .unwrap()
+            .unwrap();
+        assert_eq!(buf, b"not really a native module\n");
+    }
+
+    #[test]
+    fn test_read_asar_truncated() {
+        let buf = fs::read(fixture("app.asar")).unwrap();
+        let dir = std::env::temp_dir().join(format!("sde-asar-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // cut off the content of the last file
+        let path = dir.join("truncated.asar");
+        fs::write(&path, &buf[..buf.len() - 10]).unwrap();
+        assert!(read_asar(&path, CONFIG).is_err());
+
+        // cut off the index
+        fs::write(&path, &buf[..100]).unwrap();
+        assert!(read_asar(&path, CONFIG).is_err());
+
+        // not an archive at all
+        fs::write(&path, b"{}").unwrap();
+        assert!(read_asar(&path, CONFIG).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut config = json!({
+            "serverUrl": "https://chat.staging.signal.org",
+            "updatesUrl": "https://updates2.signal.org/desktop",
+            "cdn": {"0": "https://cdn-staging.signal.org", "3": "https://cdn3-staging.signal.org"},
+            "list": [1, 2],
+        });
+        merge(
+            &mut config,
+            json!({
+                "serverUrl": "https://chat.signal.org",
+                "cdn": {"0": "https://cdn.signal.org"},
+                "list": [3],
+            }),
+        );
+        assert_eq!(
+            config,
+            json!({
+                "serverUrl": "https://chat.signal.org",
+                "updatesUrl": "https://updates2.signal.org/desktop",
+                "cdn": {"0": "https://cdn.signal.org", "3": "https://cdn3-staging.signal.org"},
+                "list": [3],
+            })
+        );
+    }
+
+    #[test]
+    fn test_url_to_pattern() {
+        let p = url_to_pattern("https://sfu.voip.signal.org/").unwrap();
+        assert_eq!(p.to_string(), "sfu.voip.signal.org:443");
+        let p = url_to_pattern("ws://localhost:8080/ws").unwrap();
+        assert_eq!(p.to_string(), "localhost:8080");
+        let p = url_to_pattern("http://updates.signal.org").unwrap();
+        assert_eq!(p.to_string(), "updates.signal.org:80");
+        assert!(url_to_pattern("https://bad host/").is_err());
+    }
+
+    #[test]
+    fn test_endpoints() {
+        let (patterns, version) = endpoints(&fixture("app.asar")).unwrap();
+        let patterns = patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            patterns,
+            &[
+                "cdn.signal.org:443",
+                "cdn2.signal.org:443",
+                "chat.signal.org:443",
+                "sfu.voip.signal.org:443",
+                "storage.signal.org:443",
+                "updates2.signal.org:443",
+            ]
+        );
+        assert_eq!(version.as_deref(), Some("7.0.0"));
+    }
+}
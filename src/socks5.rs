@@ -5,8 +5,7 @@ use nom::number::complete::{be_u16, be_u8};
 use nom::IResult;
 use std::convert::TryFrom;
 use std::net::{Ipv4Addr, Ipv6Addr};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 #[derive(Debug)]
 pub struct Request {
@@ -43,12 +42,20 @@ enum Dest {
     Invalid,
 }
 
+#[derive(Debug, Clone, Copy)]
+pub enum Reply {
+    Succeeded = 0x00,
+    GeneralFailure = 0x01,
+    NotAllowed = 0x02,
+    HostUnreachable = 0x04,
+}
+
 enum State {
     PreAuth,
     PostAuth,
 }
 
-pub async fn handshake(sock: &mut TcpStream) -> Result<Request> {
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(sock: &mut S) -> Result<Request> {
     let mut i = 0;
     let mut buf = [0u8; 4096];
 
@@ -80,8 +87,6 @@ pub async fn handshake(sock: &mut TcpStream) -> Result<Request> {
                         bail!("Found trailing data after socks5 handshake: {:?}", bytes);
                     }
                     info!("Received socks5 request: {:?}", req.to_sock_addr()?);
-                    sock.write_all(b"\x05\x00\x00\x01\x00\x00\x00\x00\x00\x00")
-                        .await?;
                     return Ok(req);
                 }
             }
@@ -93,6 +98,15 @@ pub async fn handshake(sock: &mut TcpStream) -> Result<Request> {
     }
 }
 
+/// Finish the handshake, this needs to be sent after `handshake`
+pub async fn reply<S: AsyncWrite + Unpin>(sock: &mut S, reply: Reply) -> Result<()> {
+    debug!("Sending socks5 reply: {:?}", reply);
+    let mut msg = *b"\x05\x00\x00\x01\x00\x00\x00\x00\x00\x00";
+    msg[1] = reply as u8;
+    sock.write_all(&msg).await?;
+    Ok(())
+}
+
 fn parse_handshake_a(bytes: &[u8]) -> IResult<&[u8], ()> {
     let (bytes, _) = tag(b"\x05")(bytes)?;
     // read supported auths and discard
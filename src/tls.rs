@@ -0,0 +1,313 @@
+use crate::args::Proxy;
+use crate::errors::*;
+use nom::bytes::complete::take;
+use nom::combinator::{opt, recognize};
+use nom::number::complete::be_u8;
+use nom::IResult;
+use ring::digest::{digest, SHA256};
+use rustls::{
+    Certificate, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError,
+    WebPKIVerifier,
+};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use webpki::DNSNameRef;
+
+pub fn client_config(args: &Proxy) -> Result<ClientConfig> {
+    let mut config = ClientConfig::new();
+    config
+        .root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+
+    if args.system_roots {
+        let store = match rustls_native_certs::load_native_certs() {
+            Ok(store) => store,
+            Err((Some(store), err)) => {
+                warn!("Some system certificates couldn't be loaded: {:#}", err);
+                store
+            }
+            Err((None, err)) => return Err(err).context("Failed to load system trust store"),
+        };
+        debug!("Loaded {} certificates from system", store.roots.len());
+        config.root_store.roots.extend(store.roots);
+    }
+
+    for path in &args.ca_files {
+        let f =
+            File::open(path).with_context(|| anyhow!("Failed to open ca bundle: {:?}", path))?;
+        let (valid, invalid) = config
+            .root_store
+            .add_pem_file(&mut BufReader::new(f))
+            .map_err(|_| anyhow!("Failed to parse ca bundle: {:?}", path))?;
+        if valid == 0 {
+            bail!("No usable certificates found in ca bundle: {:?}", path);
+        }
+        debug!(
+            "Loaded {} certificates from {:?} ({} invalid)",
+            valid, path, invalid
+        );
+    }
+
+    if !args.pins.is_empty() {
+        let pins = args
+            .pins
+            .iter()
+            .map(|pin| parse_pin(pin))
+            .collect::<Result<Vec<_>>>()?;
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(PinnedVerifier { pins }));
+    }
+
+    Ok(config)
+}
+
+/// Parse a pin in the `sha256/<base64>` format known from HPKP, the prefix is optional
+fn parse_pin(pin: &str) -> Result<Vec<u8>> {
+    let b64 = pin.strip_prefix("sha256/").unwrap_or(pin);
+    let hash =
+        base64::decode(b64).with_context(|| anyhow!("Pin is not valid base64: {:?}", pin))?;
+    if hash.len() != 32 {
+        bail!("Pin is not a sha256 hash: {:?}", pin);
+    }
+    Ok(hash)
+}
+
+struct PinnedVerifier {
+    pins: Vec<Vec<u8>>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        dns_name: DNSNameRef,
+        ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        let verified = WebPKIVerifier::new().verify_server_cert(
+            roots,
+            presented_certs,
+            dns_name,
+            ocsp_response,
+        )?;
+
+        // only the leaf is pinned, the rest of the presented chain is controlled
+        // by the server and a pinned certificate could simply be appended to it
+        let leaf = presented_certs
+            .first()
+            .ok_or(TLSError::NoCertificatesPresented)?;
+        let spki = match spki(&leaf.0) {
+            Ok((_, spki)) => spki,
+            Err(_) => {
+                return Err(TLSError::General(
+                    "Failed to read public key from certificate".into(),
+                ))
+            }
+        };
+        let hash = digest(&SHA256, spki);
+        trace!("Found spki in certificate: sha256/{}", base64::encode(hash));
+        if self.pins.iter().any(|pin| pin[..] == *hash.as_ref()) {
+            debug!("Certificate matched pinned key");
+            Ok(verified)
+        } else {
+            Err(TLSError::General(
+                "The presented certificate didn't match a pinned public key".into(),
+            ))
+        }
+    }
+}
+
+/// Read a der length, the indefinite form and lengths that don't fit into 4
+/// bytes aren't valid in a certificate
+fn der_len(bytes: &[u8]) -> IResult<&[u8], usize> {
+    let (rest, len) = be_u8(bytes)?;
+    if len < 0x80 {
+        return Ok((rest, len as usize));
+    }
+    let n = len & 0x7f;
+    if n == 0 || n > 4 {
+        return Err(nom::Err::Error((bytes, nom::error::ErrorKind::LengthValue)));
+    }
+    let (rest, len) = take(n)(rest)?;
+    let len = len.iter().fold(0usize, |acc, b| (acc << 8) | *b as usize);
+    Ok((rest, len))
+}
+
+/// Read a single der value, returns the tag and its content
+fn der(bytes: &[u8]) -> IResult<&[u8], (u8, &[u8])> {
+    let (bytes, tag) = be_u8(bytes)?;
+    let (bytes, len) = der_len(bytes)?;
+    let (bytes, content) = take(len)(bytes)?;
+    Ok((bytes, (tag, content)))
+}
+
+fn der_tag(expected: u8) -> impl Fn(&[u8]) -> IResult<&[u8], &[u8]> {
+    move |bytes| {
+        let (rest, (tag, content)) = der(bytes)?;
+        if tag != expected {
+            return Err(nom::Err::Error((bytes, nom::error::ErrorKind::Tag)));
+        }
+        Ok((rest, content))
+    }
+}
+
+/// Extract the encoded SubjectPublicKeyInfo from a der certificate
+fn spki(cert: &[u8]) -> IResult<&[u8], &[u8]> {
+    let (_, cert) = der_tag(0x30)(cert)?;
+    let (_, tbs) = der_tag(0x30)(cert)?;
+    // version
+    let (tbs, _) = opt(der_tag(0xa0))(tbs)?;
+    // serial number, signature algorithm, issuer, validity, subject
+    let (tbs, _) = der(tbs)?;
+    let (tbs, _) = der(tbs)?;
+    let (tbs, _) = der(tbs)?;
+    let (tbs, _) = der(tbs)?;
+    let (tbs, _) = der(tbs)?;
+    recognize(der_tag(0x30))(tbs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> Vec<u8> {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tls/");
+        std::fs::read(format!("{}{}", path, name)).unwrap()
+    }
+
+    fn pin(cert: &[u8]) -> String {
+        let (_, spki) = spki(cert).unwrap();
+        base64::encode(digest(&SHA256, spki))
+    }
+
+    // the expected values were calculated with
+    // openssl x509 -inform der -in ec.der -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary | base64
+    #[test]
+    fn test_spki_ec() {
+        let cert = fixture("ec.der");
+        let (_, spki) = spki(&cert).unwrap();
+        assert_eq!(spki.len(), 91);
+        assert_eq!(pin(&cert), "+CdRxxOZcagXLcH+uoTxevcHxoQTX7iDyZXlj88fsOw=");
+    }
+
+    #[test]
+    fn test_spki_rsa() {
+        let cert = fixture("rsa.der");
+        let (_, spki) = spki(&cert).unwrap();
+        assert_eq!(spki.len(), 294);
+        assert_eq!(pin(&cert), "rDHJvPaGvA4veVcd9hUrx/UITgkN3fXSmkiZwC2k+cs=");
+    }
+
+    #[test]
+    fn test_spki_truncated() {
+        let cert = fixture("rsa.der");
+        for len in [0, 1, 4, 100, cert.len() / 2, cert.len() - 1].iter() {
+            assert!(spki(&cert[..*len]).is_err(), "accepted {} bytes", len);
+        }
+    }
+
+    #[test]
+    fn test_spki_not_a_certificate() {
+        assert!(spki(b"\x04\x03abc").is_err());
+        assert!(spki(b"\x30\x03\x02\x01\x01").is_err());
+        assert!(spki(b"-----BEGIN CERTIFICATE-----").is_err());
+    }
+
+    #[test]
+    fn test_der_len() {
+        assert_eq!(der_len(b"\x05rest").unwrap(), (&b"rest"[..], 5));
+        assert_eq!(der_len(b"\x81\x80").unwrap(), (&b""[..], 0x80));
+        assert_eq!(der_len(b"\x82\x01\x26").unwrap(), (&b""[..], 0x126));
+        assert_eq!(
+            der_len(b"\x84\x01\x02\x03\x04").unwrap(),
+            (&b""[..], 0x0102_0304)
+        );
+    }
+
+    #[test]
+    fn test_der_len_malformed() {
+        // indefinite length
+        assert!(der_len(b"\x80").is_err());
+        // longer than 4 bytes, would overflow on 32 bit
+        assert!(der_len(b"\x89\x01\x00\x00\x00\x00\x00\x00\x00\x00").is_err());
+        // missing length bytes
+        assert!(der_len(b"\x82\x01").is_err());
+        assert!(der_len(b"").is_err());
+    }
+
+    #[test]
+    fn test_der_content_too_short() {
+        assert!(der(b"\x30\x05abc").is_err());
+        assert!(der(b"\x30\x84\xff\xff\xff\xffabc").is_err());
+        assert_eq!(
+            der(b"\x30\x03abcd").unwrap(),
+            (&b"d"[..], (0x30, &b"abc"[..]))
+        );
+    }
+
+    #[test]
+    fn test_parse_pin() {
+        let pin = "sha256/+CdRxxOZcagXLcH+uoTxevcHxoQTX7iDyZXlj88fsOw=";
+        assert_eq!(parse_pin(pin).unwrap().len(), 32);
+        assert_eq!(parse_pin(&pin[7..]).unwrap(), parse_pin(pin).unwrap());
+        assert!(parse_pin("sha256/AAAA").is_err());
+        assert!(parse_pin("sha256/not base64").is_err());
+    }
+
+    fn verify(pins: &[&[u8]], presented: &[&[u8]]) -> Result<ServerCertVerified, TLSError> {
+        let mut roots = RootCertStore::empty();
+        roots.add(&Certificate(fixture("ca.der"))).unwrap();
+        let verifier = PinnedVerifier {
+            pins: pins
+                .iter()
+                .map(|cert| parse_pin(&pin(cert)).unwrap())
+                .collect(),
+        };
+        let presented = presented
+            .iter()
+            .map(|cert| Certificate(cert.to_vec()))
+            .collect::<Vec<_>>();
+        let name = DNSNameRef::try_from_ascii_str("proxy.example").unwrap();
+        verifier.verify_server_cert(&roots, &presented, name, &[])
+    }
+
+    #[test]
+    fn test_pinned_leaf() {
+        let leaf = fixture("leaf.der");
+        assert!(verify(&[&leaf], &[&leaf]).is_ok());
+    }
+
+    #[test]
+    fn test_pinned_leaf_among_others() {
+        let leaf = fixture("leaf.der");
+        let other = fixture("ec.der");
+        assert!(verify(&[&other, &leaf], &[&leaf]).is_ok());
+    }
+
+    #[test]
+    fn test_pin_mismatch() {
+        let leaf = fixture("leaf.der");
+        let other = fixture("ec.der");
+        assert!(verify(&[&other], &[&leaf]).is_err());
+    }
+
+    #[test]
+    fn test_pinned_cert_appended_to_chain() {
+        // the chain is valid and contains the pinned certificate, but not as leaf
+        let leaf = fixture("leaf.der");
+        let pinned = fixture("rsa.der");
+        assert!(verify(&[&pinned], &[&leaf, &pinned]).is_err());
+        // the intermediate/root isn't pinned either
+        let ca = fixture("ca.der");
+        assert!(verify(&[&ca], &[&leaf, &ca]).is_err());
+    }
+
+    #[test]
+    fn test_pinned_leaf_still_needs_valid_chain() {
+        // pins are checked in addition to the regular verification
+        let other = fixture("ec.der");
+        assert!(verify(&[&other], &[&other]).is_err());
+    }
+}
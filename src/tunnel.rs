@@ -1,32 +1,181 @@
-use crate::args::Tunnel;
+use crate::args::{SubCommand, Tunnel};
+use crate::config::Loader;
 use crate::connect;
 use crate::errors::*;
-use crate::rules;
-use crate::socks5;
+use crate::learn::{self, Learner};
+use crate::limits::Throttles;
+use crate::listener::{Listener, PeerAddr, Stream};
+use crate::peercred;
+use crate::pool::Health;
+use crate::probe;
+use crate::reload::{self, Shared};
+use crate::rules::{self, Action, Rule};
+use crate::socks5::{self, Reply};
+use crate::upstream::Upstream;
 use futures::{select, FutureExt};
-use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::net::TcpListener;
-use tokio::net::TcpStream;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time;
+
+struct State {
+    args: Tunnel,
+    rules: Vec<Rule>,
+    throttles: Throttles,
+}
+
+impl State {
+    /// The buckets are shared with the previous state so reloads don't reset them
+    fn new(args: Tunnel, throttles: &Throttles) -> Result<State> {
+        let rules = args.rules()?;
+        let throttles = Throttles {
+            upload: throttles.upload.with_rate(args.upload_limit),
+            download: throttles.download.with_rate(args.download_limit),
+        };
+        Ok(State {
+            args,
+            rules,
+            throttles,
+        })
+    }
+
+    /// The first matching rule and its action, unmatched requests go direct or
+    /// are refused (`None`) in strict mode
+    fn route(&self, addr: &str) -> (Option<&Rule>, Option<&Action>) {
+        let rule = rules::evaluate(addr, &self.rules);
+        let action = match rule {
+            Some(rule) => Some(&rule.action),
+            None if self.args.strict => None,
+            None => Some(&Action::Direct),
+        };
+        (rule, action)
+    }
+}
+
+/// Check if the local process is allowed to use the tunnel, if no uids or gids
+/// are configured everybody is
+fn is_authorized(args: &Tunnel, sock: &Stream, addr: &PeerAddr) -> bool {
+    if args.allowed_uids.is_empty() && args.allowed_gids.is_empty() {
+        return true;
+    }
+
+    match peercred::lookup(sock, !args.allowed_gids.is_empty()) {
+        Ok(creds) => {
+            debug!("Connection from {} has credentials {:?}", addr, creds);
+            if creds.is_allowed(&args.allowed_uids, &args.allowed_gids) {
+                true
+            } else {
+                warn!("Refusing connection from {} with {:?}", addr, creds);
+                false
+            }
+        }
+        Err(err) => {
+            warn!(
+                "Refusing connection from {}, failed to determine peer credentials: {:#}",
+                addr, err
+            );
+            false
+        }
+    }
+}
+
+async fn process(
+    state: Arc<State>,
+    health: Arc<Health>,
+    learner: Arc<Learner>,
+    mut sock: Stream,
+    addr: PeerAddr,
+) -> Result<()> {
+    if !is_authorized(&state.args, &sock, &addr) {
+        return Ok(());
+    }
 
-async fn process(args: Arc<Tunnel>, mut sock: TcpStream, _addr: SocketAddr) -> Result<()> {
     let req = socks5::handshake(&mut sock).await?;
-    let addr = req.to_sock_addr()?;
+    let addr = rules::canonicalize(&req.to_sock_addr()?)?;
 
-    if rules::matches(&addr, &args.forward) {
-        info!("Forwarding connection to proxy: {:?}", addr);
-        connect::run_with(&args.proxy, &addr, sock).await
-    } else {
-        info!("Creating direct connection");
-        let remote = connect::connect_dns(&req.to_host_addr()?, req.port).await?;
-        relay(remote, sock).await
+    let (rule, action) = state.route(&addr);
+    let action = match action {
+        Some(action) => action,
+        None => {
+            warn!("Refusing connection to {:?}, no rule matched", addr);
+            learner.record(&addr, "refused", None);
+            return socks5::reply(&mut sock, Reply::NotAllowed).await;
+        }
+    };
+    learner.record(&addr, &action.to_string(), rule);
+
+    match action {
+        Action::Proxy => {
+            info!("Forwarding connection to proxy: {:?}", addr);
+            socks5::reply(&mut sock, Reply::Succeeded).await?;
+            connect::run_with(
+                &state.args.proxy,
+                &health,
+                None,
+                &addr,
+                sock,
+                &state.throttles,
+            )
+            .await
+        }
+        Action::Via(name) => {
+            info!("Forwarding connection to proxy {:?}: {:?}", name, addr);
+            socks5::reply(&mut sock, Reply::Succeeded).await?;
+            connect::run_with(
+                &state.args.proxy,
+                &health,
+                Some(name),
+                &addr,
+                sock,
+                &state.throttles,
+            )
+            .await
+        }
+        Action::Direct => {
+            info!("Creating direct connection");
+            let upstream = state.args.proxy.upstream.as_ref();
+            let upstream = upstream.filter(|_| state.args.direct_upstream);
+            direct(&state, sock, &req, upstream).await
+        }
+        Action::Upstream(upstream) => {
+            info!("Forwarding connection to upstream {}: {:?}", upstream, addr);
+            direct(&state, sock, &req, Some(upstream)).await
+        }
+        Action::Block | Action::Allow => {
+            info!("Blocking connection to {:?}", addr);
+            socks5::reply(&mut sock, Reply::NotAllowed).await
+        }
     }
 }
 
+/// Connect to the destination without the proxy, optionally through an upstream
+async fn direct(
+    state: &State,
+    mut sock: Stream,
+    req: &socks5::Request,
+    upstream: Option<&Upstream>,
+) -> Result<()> {
+    let host = req.to_host_addr()?;
+    let remote = match upstream {
+        Some(upstream) => upstream.connect(&host, req.port).await,
+        None => connect::connect_dns(&host, req.port).await,
+    };
+    let remote = match remote {
+        Ok(remote) => remote,
+        Err(err) => {
+            socks5::reply(&mut sock, Reply::HostUnreachable).await?;
+            return Err(err);
+        }
+    };
+    socks5::reply(&mut sock, Reply::Succeeded).await?;
+    relay(remote, sock, &state.throttles).await
+}
+
 async fn relay<A: AsyncRead + AsyncWrite + Unpin, B: AsyncRead + AsyncWrite + Unpin>(
     mut remote: A,
     mut local: B,
+    throttles: &Throttles,
 ) -> Result<()> {
     let mut buf_a = [0u8; 4096];
     let mut buf_b = [0u8; 4096];
@@ -41,7 +190,8 @@ async fn relay<A: AsyncRead + AsyncWrite + Unpin, B: AsyncRead + AsyncWrite + Un
                 }
                 let msg = &buf_a[..n];
                 trace!("Recv: {:?}", msg);
-                local.write_all(&msg).await?;
+                throttles.download.consume(n).await;
+                local.write_all(msg).await?;
             },
             n = local.read(&mut buf_b).fuse() => {
                 let n = n?;
@@ -51,7 +201,8 @@ async fn relay<A: AsyncRead + AsyncWrite + Unpin, B: AsyncRead + AsyncWrite + Un
                 }
                 let msg = &buf_b[..n];
                 trace!("Send: {:?}", msg);
-                remote.write_all(&msg).await?;
+                throttles.upload.consume(n).await;
+                remote.write_all(msg).await?;
             },
         };
     }
@@ -60,20 +211,142 @@ async fn relay<A: AsyncRead + AsyncWrite + Unpin, B: AsyncRead + AsyncWrite + Un
     Ok(())
 }
 
-pub async fn run(args: Tunnel) -> Result<()> {
-    let mut listener = TcpListener::bind(&args.bind).await?;
-    info!("Started socks5 server on {:?}", args.bind);
+/// Measure the latency of every proxy in the background, the interval is
+/// re-read after every round so reloads apply
+async fn probe_loop(state: Arc<Shared<State>>, health: Arc<Health>) {
+    loop {
+        let state = state.load();
+        match state.args.probe_interval() {
+            Some(interval) => {
+                probe::probe_all(&state.args.proxy, &health).await;
+                time::delay_for(interval).await;
+            }
+            None => time::delay_for(Duration::from_secs(10)).await,
+        }
+    }
+}
+
+pub async fn run(args: Tunnel, loader: Loader) -> Result<()> {
+    let bind = args.bind.clone();
+    let socket_mode = args.socket_mode;
+    let learn = args.learn.clone();
+    let throttles = Throttles::default();
+    let health = Arc::new(Health::default());
+    let learner = Arc::new(Learner::load(args.learn.clone())?);
+    let state = Arc::new(Shared::new(State::new(args, &throttles)?));
 
-    let config = Arc::new(args);
+    let mut listener = Listener::bind(&bind, socket_mode).await?;
+    info!("Started socks5 server on {:?}", bind);
+
+    {
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let path = loader.path();
+            let reload = || {
+                let args = match loader.load()?.subcommand {
+                    SubCommand::Tunnel(args) => args,
+                    _ => bail!("Unexpected subcommand"),
+                };
+                if args.bind != bind {
+                    warn!("Changing the bind address requires a restart");
+                }
+                if args.learn != learn {
+                    warn!("Changing the learn file requires a restart");
+                }
+                state.store(State::new(args, &throttles)?);
+                Ok(())
+            };
+            if let Err(err) = reload::watch(path, reload).await {
+                error!("Failed to watch for config changes: {:#}", err);
+            }
+        });
+    }
+
+    tokio::spawn(probe_loop(Arc::clone(&state), Arc::clone(&health)));
+    tokio::spawn(learn::persist(Arc::clone(&learner)));
+
+    let mut interrupt =
+        signal(SignalKind::interrupt()).context("Failed to setup signal handler")?;
+    let mut terminate =
+        signal(SignalKind::terminate()).context("Failed to setup signal handler")?;
     loop {
-        let (stream, addr) = listener.accept().await?;
-        debug!("Connection from {:?}", addr);
-        let config = Arc::clone(&config);
+        let (stream, addr) = tokio::select! {
+            res = listener.accept() => res?,
+            _ = interrupt.recv() => break,
+            _ = terminate.recv() => break,
+        };
+        debug!("Connection from {}", addr);
+        let state = state.load();
+        let health = Arc::clone(&health);
+        let learner = Arc::clone(&learner);
         // Spawn our handler to be run asynchronously.
         tokio::spawn(async move {
-            if let Err(e) = process(config, stream, addr).await {
+            if let Err(e) = process(state, health, learner, stream, addr).await {
                 warn!("An error occurred; error = {:#}", e);
             }
         });
     }
+
+    info!("Shutting down");
+    // don't lose destinations learned since the last save
+    learner.save()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    fn state(args: &[&str]) -> State {
+        let args = Tunnel::from_iter(std::iter::once("tunnel").chain(args.iter().copied()));
+        State::new(args, &Throttles::default()).unwrap()
+    }
+
+    fn action(state: &State, addr: &str) -> Option<Action> {
+        state.route(addr).1.cloned()
+    }
+
+    #[test]
+    fn test_rules_before_forward() {
+        let state = state(&["-R", "example.com:* -> block", "-F", "*"]);
+        assert_eq!(action(&state, "example.com:443"), Some(Action::Block));
+        assert_eq!(action(&state, "example.org:443"), Some(Action::Proxy));
+    }
+
+    #[test]
+    fn test_rules_before_profile() {
+        let state = state(&["--profile", "signal", "-R", "cdn.signal.org:443 -> direct"]);
+        assert_eq!(action(&state, "cdn.signal.org:443"), Some(Action::Direct));
+        assert_eq!(
+            action(&state, "textsecure-service.whispersystems.org:443"),
+            Some(Action::Proxy)
+        );
+    }
+
+    #[test]
+    fn test_first_rule_wins() {
+        let state = state(&[
+            "-R",
+            "*.example.com:443 -> direct",
+            "-R",
+            "www.example.com:* -> block",
+        ]);
+        assert_eq!(action(&state, "www.example.com:443"), Some(Action::Direct));
+        assert_eq!(action(&state, "www.example.com:80"), Some(Action::Block));
+    }
+
+    #[test]
+    fn test_unmatched_goes_direct() {
+        let state = state(&["-F", "*.signal.org:443"]);
+        let (rule, action) = state.route("example.com:443");
+        assert!(rule.is_none());
+        assert_eq!(action, Some(&Action::Direct));
+    }
+
+    #[test]
+    fn test_unmatched_refused_in_strict_mode() {
+        let state = state(&["--strict", "-F", "*.signal.org:443"]);
+        assert_eq!(action(&state, "example.com:443"), None);
+        assert_eq!(action(&state, "cdn.signal.org:443"), Some(Action::Proxy));
+    }
 }